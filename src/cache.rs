@@ -1,66 +1,197 @@
+use crate::package::PackageOptions;
 use anyhow::Result;
-use crev_recursive_digest;
+use blake2::{Blake2b, Digest};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
 use log::debug;
-use log::error;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct _Cache {
+/// Cache keys of packages as of their last successful preparation
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Cache {
     last_updated_version: HashMap<PathBuf, String>,
 }
 
-impl _Cache {
-    pub fn _new() -> _Cache {
-        _Cache {
+impl Cache {
+    pub fn new() -> Cache {
+        Cache {
             last_updated_version: HashMap::new(),
         }
     }
 
-    pub fn _has_changed(&self, path: PathBuf) -> bool {
-        let hash = match _get_package_hash(path.clone()) {
-            Ok(digest) => digest,
-            Err(e) => {
-                error!("Error getting package hash: {:?}", e);
-                return false;
-            }
-        };
-        self.last_updated_version
-            .get(&path)
-            .map_or(false, |version| version.clone() == hash)
+    pub fn has_changed(&self, path: &Path, key: &str) -> bool {
+        self.last_updated_version.get(path).map(String::as_str) != Some(key)
     }
 
-    pub fn _update(&mut self, path: PathBuf) -> Result<()> {
-        let digest = _get_package_hash(path.clone())?;
-        self.last_updated_version.insert(path.clone(), digest);
-        Ok(())
+    pub fn update(&mut self, path: &Path, key: String) {
+        self.last_updated_version.insert(path.to_path_buf(), key);
+    }
+
+    pub fn remove(&mut self, path: &Path) {
+        self.last_updated_version.remove(path);
+    }
+
+    pub fn clear(&mut self) {
+        self.last_updated_version.clear();
     }
 }
 
-pub fn _get_package_hash(path: PathBuf) -> Result<String> {
-    let rdigest = crev_recursive_digest::RecursiveDigest::<blake2::Blake2b, _, _>::new()
-        .additional_data(|entry, writer| {
-            let metadata = entry.metadata()?;
-            writer.input(&_metadata_to_u16(&metadata).to_be_bytes());
-            Ok(())
-        })
-        .build();
-    let digest = rdigest.get_digest_of(&path)?;
-    debug!("Digest: {:?} for {}", hex::encode(&digest), path.display());
+/// Directories which are never hashed, as they are outputs of preparing a package rather than
+/// inputs to it
+const EXCLUDED_DIRS: [&str; 3] = ["node_modules", "dist", ".git"];
+
+/// Hash the source inputs of the package at the given path.
+///
+/// Files ignored by `.gitignore` are skipped, and `node_modules`, `dist` and `.git` are always
+/// skipped. `.npmignore` and the package.json `files` field are not used, as they select what is
+/// published, which usually leaves out the sources. The package's `include` globs, if any,
+/// restrict hashing to matching files and its `exclude` globs remove files. Globs are matched
+/// against paths relative to the package directory.
+pub fn get_package_hash(path: &Path, options: &PackageOptions) -> Result<String> {
+    let mut hasher = Blake2b::new();
+    // changing how a package is prepared invalidates its previous preparation
+    for command in &[options.get_install_command(), options.get_build_command()] {
+        hasher.input(command.as_bytes());
+        hasher.input([0]);
+    }
+    for relative_path in get_hash_inputs(path, options)? {
+        let mut file_path = path.to_path_buf();
+        file_path.push(&relative_path);
+        let metadata = fs::metadata(&file_path)?;
+
+        hasher.input(relative_path.to_string_lossy().as_bytes());
+        hasher.input([0]);
+        hasher.input(metadata_to_u16(&metadata).to_be_bytes());
+        hasher.input(Blake2b::digest(&fs::read(&file_path)?));
+    }
+    let digest = hasher.result();
+    debug!("Digest: {:?} for {}", hex::encode(digest), path.display());
+
+    Ok(hex::encode(digest))
+}
+
+/// The sorted paths of the files, relative to the package directory, which make up the source
+/// inputs of the package
+pub fn get_hash_inputs(path: &Path, options: &PackageOptions) -> Result<Vec<PathBuf>> {
+    let include = build_glob_set(&options.include)?;
+    let exclude = build_glob_set(&options.exclude)?;
+
+    let mut inputs: BTreeSet<PathBuf> = BTreeSet::new();
+    let mut builder = WalkBuilder::new(path);
+    builder
+        .hidden(false)
+        .require_git(false)
+        .filter_entry(|entry| {
+            entry.depth() == 0 || !EXCLUDED_DIRS.iter().any(|dir| entry.file_name() == *dir)
+        });
+
+    for entry in builder.build() {
+        let entry = entry?;
+        if !entry
+            .file_type()
+            .is_some_and(|file_type| file_type.is_file())
+        {
+            continue;
+        }
+
+        let relative_path = entry.path().strip_prefix(path)?.to_path_buf();
+        if (include.is_empty() || include.is_match(&relative_path))
+            && !exclude.is_match(&relative_path)
+        {
+            inputs.insert(relative_path);
+        }
+    }
 
-    Ok(hex::encode(&digest))
+    // dependency specs always affect the build
+    inputs.insert(PathBuf::from("package.json"));
+    Ok(inputs.into_iter().collect())
+}
+
+fn build_glob_set(globs: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob)?);
+    }
+    Ok(builder.build()?)
+}
+
+/// The sorted paths of the files, relative to the package directory, which make up the build
+/// output of the package: those listed by the package.json `files` field, or everything in `dist`
+/// if it has none
+pub fn get_output_files(path: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries = get_files_field(path);
+    if entries.is_empty() {
+        entries.push("dist".to_string());
+    }
+    let outputs = build_glob_set(
+        &entries
+            .iter()
+            .flat_map(|entry| {
+                let entry = entry.trim_start_matches("./").trim_end_matches('/');
+                vec![entry.to_string(), format!("{}/**", entry)]
+            })
+            .collect::<Vec<String>>(),
+    )?;
+
+    let mut files: BTreeSet<PathBuf> = BTreeSet::new();
+    let mut builder = WalkBuilder::new(path);
+    builder
+        .standard_filters(false)
+        .filter_entry(|entry| entry.file_name() != "node_modules" && entry.file_name() != ".git");
+    for entry in builder.build() {
+        let entry = entry?;
+        if !entry
+            .file_type()
+            .is_some_and(|file_type| file_type.is_file())
+        {
+            continue;
+        }
+
+        let relative_path = entry.path().strip_prefix(path)?.to_path_buf();
+        if outputs.is_match(&relative_path) && relative_path != Path::new("package.json") {
+            files.insert(relative_path);
+        }
+    }
+    Ok(files.into_iter().collect())
+}
+
+/// Hash the contents of the file
+pub fn get_file_hash(path: &Path) -> Result<String> {
+    Ok(hex::encode(Blake2b::digest(&fs::read(path)?)))
+}
+
+fn get_files_field(path: &Path) -> Vec<String> {
+    let mut package_json = path.to_path_buf();
+    package_json.push("package.json");
+
+    fs::read(package_json)
+        .ok()
+        .and_then(|contents| serde_json::from_slice::<Value>(&contents).ok())
+        .and_then(|data| match data.get("files") {
+            Some(Value::Array(files)) => Some(
+                files
+                    .iter()
+                    .filter_map(|file| file.as_str().map(String::from))
+                    .collect(),
+            ),
+            _ => None,
+        })
+        .unwrap_or_default()
 }
 
 #[cfg(unix)]
-fn _metadata_to_u16(metadata: &std::fs::Metadata) -> u16 {
+fn metadata_to_u16(metadata: &std::fs::Metadata) -> u16 {
     let permissions = metadata.permissions();
     use std::os::unix::fs::PermissionsExt;
     (permissions.mode() & 0x1ff) as u16
 }
 
 #[cfg(not(unix))]
-fn _metadata_to_u16(metadata: &std::fs::Metadata) -> u16 {
+fn metadata_to_u16(metadata: &std::fs::Metadata) -> u16 {
     let permissions = metadata.permissions();
     // TODO: what else to do on Windows?
     match (permissions.readonly(), metadata.is_dir()) {
@@ -0,0 +1,150 @@
+use crate::package::DependencyType;
+use anyhow::{bail, Result};
+use serde_json::json;
+use std::path::PathBuf;
+
+/// The formats a dependency graph can be exported in
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+    Json,
+}
+
+impl GraphFormat {
+    pub fn parse(format: &str) -> Result<GraphFormat> {
+        match format {
+            "dot" => Ok(GraphFormat::Dot),
+            "mermaid" => Ok(GraphFormat::Mermaid),
+            "json" => Ok(GraphFormat::Json),
+            _ => bail!(
+                "Unknown graph format {}, expected dot, mermaid or json",
+                format
+            ),
+        }
+    }
+}
+
+/// Registered packages and the dependencies between them, sorted by path so exports are stable
+pub struct Graph {
+    /// Name and path of each package
+    nodes: Vec<(String, PathBuf)>,
+    /// Indices into nodes of each dependent and the package it depends on, with the package.json
+    /// sections listing the dependency
+    edges: Vec<(usize, usize, Vec<DependencyType>)>,
+}
+
+impl Graph {
+    pub fn new(
+        mut nodes: Vec<(String, PathBuf)>,
+        edges: &[(PathBuf, PathBuf, Vec<DependencyType>)],
+    ) -> Graph {
+        nodes.sort_by(|a, b| a.1.cmp(&b.1));
+        let index = |path: &PathBuf| nodes.iter().position(|(_, node)| node == path);
+
+        let mut indexed: Vec<(usize, usize, Vec<DependencyType>)> = edges
+            .iter()
+            .filter_map(|(from, to, dependency_types)| {
+                Some((index(from)?, index(to)?, dependency_types.clone()))
+            })
+            .collect();
+        indexed.sort_unstable();
+
+        Graph {
+            nodes,
+            edges: indexed,
+        }
+    }
+
+    pub fn render(&self, format: &GraphFormat) -> String {
+        match format {
+            GraphFormat::Dot => self.to_dot(),
+            GraphFormat::Mermaid => self.to_mermaid(),
+            GraphFormat::Json => self.to_json(),
+        }
+    }
+
+    fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph lpm {\n");
+        for (name, path) in &self.nodes {
+            dot.push_str(&format!(
+                "    \"{}\" [label=\"{}\"];\n",
+                escape_dot(&path.to_string_lossy()),
+                escape_dot(name)
+            ));
+        }
+        for (from, to, dependency_types) in &self.edges {
+            let attributes = match get_label(dependency_types) {
+                Some(label) => format!(" [label=\"{}\", style=dashed]", label),
+                None => String::new(),
+            };
+            dot.push_str(&format!(
+                "    \"{}\" -> \"{}\"{};\n",
+                escape_dot(&self.nodes[*from].1.to_string_lossy()),
+                escape_dot(&self.nodes[*to].1.to_string_lossy()),
+                attributes
+            ));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    fn to_mermaid(&self) -> String {
+        // node ids are indices, as mermaid ids cannot contain most characters in paths or names
+        let mut mermaid = String::from("graph TD\n");
+        for (index, (name, _)) in self.nodes.iter().enumerate() {
+            mermaid.push_str(&format!(
+                "    n{}[\"{}\"]\n",
+                index,
+                name.replace('"', "#quot;")
+            ));
+        }
+        for (from, to, dependency_types) in &self.edges {
+            match get_label(dependency_types) {
+                Some(label) => {
+                    mermaid.push_str(&format!("    n{} -.->|{}| n{}\n", from, label, to))
+                }
+                None => mermaid.push_str(&format!("    n{} --> n{}\n", from, to)),
+            }
+        }
+        mermaid
+    }
+
+    fn to_json(&self) -> String {
+        let packages: Vec<_> = self
+            .nodes
+            .iter()
+            .map(|(name, path)| json!({ "name": name, "path": path }))
+            .collect();
+        let dependencies: Vec<_> = self
+            .edges
+            .iter()
+            .map(|(from, to, dependency_types)| {
+                json!({
+                    "dependent": self.nodes[*from].1,
+                    "dependency": self.nodes[*to].1,
+                    "sections": dependency_types,
+                })
+            })
+            .collect();
+
+        let graph = json!({ "packages": packages, "dependencies": dependencies });
+        format!("{}\n", serde_json::to_string_pretty(&graph).unwrap())
+    }
+}
+
+/// Label for dependencies not listed in the dependencies section, naming the sections they are
+/// listed in
+fn get_label(dependency_types: &[DependencyType]) -> Option<String> {
+    if dependency_types.contains(&DependencyType::Dependencies) {
+        return None;
+    }
+    let keys: Vec<&str> = dependency_types
+        .iter()
+        .map(DependencyType::get_key)
+        .collect();
+    Some(keys.join(", "))
+}
+
+fn escape_dot(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,258 @@
+use std::ops::Range;
+
+/// Byte range of the value at the path of object keys in the JSON text. When an object repeats a
+/// key the last value is used, matching how the text is parsed
+pub fn find_value(text: &str, path: &[&str]) -> Option<Range<usize>> {
+    let mut start = 0;
+    for key in path {
+        start = find_member(text, start, key)?.start;
+    }
+
+    let mut scanner = Scanner::new(text, start);
+    scanner.skip_whitespace();
+    let start = scanner.pos;
+    scanner.value()?;
+    Some(start..scanner.pos)
+}
+
+/// Replace the JSON value at the path of object keys with the serialized value, leaving the rest
+/// of the text untouched. Returns whether the value was found
+pub fn replace_value(text: &mut String, path: &[&str], value: &serde_json::Value) -> bool {
+    match find_value(text, path) {
+        Some(range) => {
+            text.replace_range(range, &value.to_string());
+            true
+        }
+        None => false,
+    }
+}
+
+/// Range of the value of the member with the key in the object starting at the given offset
+fn find_member(text: &str, start: usize, key: &str) -> Option<Range<usize>> {
+    let mut scanner = Scanner::new(text, start);
+    scanner.skip_whitespace();
+    scanner.expect(b'{')?;
+    scanner.skip_whitespace();
+    if scanner.peek() == Some(b'}') {
+        return None;
+    }
+
+    let mut found = None;
+    loop {
+        scanner.skip_whitespace();
+        let member_key = scanner.string()?;
+        scanner.skip_whitespace();
+        scanner.expect(b':')?;
+        scanner.skip_whitespace();
+        let member_value = scanner.value()?;
+        if serde_json::from_str::<String>(&text[member_key]).ok()? == key {
+            found = Some(member_value);
+        }
+
+        scanner.skip_whitespace();
+        match scanner.next()? {
+            b',' => continue,
+            b'}' => return found,
+            _ => return None,
+        }
+    }
+}
+
+struct Scanner<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(text: &'a str, pos: usize) -> Scanner<'a> {
+        Scanner {
+            bytes: text.as_bytes(),
+            pos,
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let byte = self.peek()?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    fn expect(&mut self, expected: u8) -> Option<()> {
+        match self.next()? {
+            byte if byte == expected => Some(()),
+            _ => None,
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\r' | b'\n') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    /// Skip the value at the current position, returning its range
+    fn value(&mut self) -> Option<Range<usize>> {
+        let start = self.pos;
+        match self.peek()? {
+            b'"' => {
+                self.string()?;
+            }
+            b'{' | b'[' => {
+                let mut depth = 0;
+                loop {
+                    match self.peek()? {
+                        b'"' => {
+                            self.string()?;
+                            continue;
+                        }
+                        b'{' | b'[' => depth += 1,
+                        b'}' | b']' => depth -= 1,
+                        _ => {}
+                    }
+                    self.pos += 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+            }
+            _ => {
+                // numbers, true, false and null
+                while let Some(byte) = self.peek() {
+                    if matches!(byte, b',' | b'}' | b']' | b' ' | b'\t' | b'\r' | b'\n') {
+                        break;
+                    }
+                    self.pos += 1;
+                }
+            }
+        }
+        Some(start..self.pos)
+    }
+
+    /// Skip the string at the current position, returning its range including the quotes
+    fn string(&mut self) -> Option<Range<usize>> {
+        let start = self.pos;
+        self.expect(b'"')?;
+        loop {
+            match self.next()? {
+                b'\\' => {
+                    self.next()?;
+                }
+                b'"' => return Some(start..self.pos),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// The text after replacing the value at the path with the string, if it was found
+    fn replace(text: &str, path: &[&str], value: &str) -> Option<String> {
+        let mut text = text.to_string();
+        if replace_value(&mut text, path, &json!(value)) {
+            Some(text)
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn keeps_crlf_line_endings() {
+        let text = "{\r\n  \"dependencies\": {\r\n    \"a\": \"1.0.0\"\r\n  }\r\n}\r\n";
+        assert_eq!(
+            replace(text, &["dependencies", "a"], "file:../a").unwrap(),
+            "{\r\n  \"dependencies\": {\r\n    \"a\": \"file:../a\"\r\n  }\r\n}\r\n"
+        );
+    }
+
+    #[test]
+    fn keeps_tab_indentation() {
+        let text = "{\n\t\"dependencies\": {\n\t\t\"a\": \"1.0.0\",\n\t\t\"b\": \"2.0.0\"\n\t}\n}";
+        assert_eq!(
+            replace(text, &["dependencies", "b"], "file:../b").unwrap(),
+            "{\n\t\"dependencies\": {\n\t\t\"a\": \"1.0.0\",\n\t\t\"b\": \"file:../b\"\n\t}\n}"
+        );
+    }
+
+    #[test]
+    fn ignores_nested_keys_of_the_same_name() {
+        let text =
+            r#"{"config": {"a": "x"}, "dependencies": {"config": {"a": "y"}, "a": "1.0.0"}}"#;
+        assert_eq!(
+            replace(text, &["dependencies", "a"], "2.0.0").unwrap(),
+            r#"{"config": {"a": "x"}, "dependencies": {"config": {"a": "y"}, "a": "2.0.0"}}"#
+        );
+    }
+
+    #[test]
+    fn skips_braces_and_quotes_inside_strings() {
+        let text = r#"{"scripts": {"build": "echo \"}{\" ]"}, "dependencies": {"a": "1.0.0"}}"#;
+        assert_eq!(
+            replace(text, &["dependencies", "a"], "2.0.0").unwrap(),
+            r#"{"scripts": {"build": "echo \"}{\" ]"}, "dependencies": {"a": "2.0.0"}}"#
+        );
+    }
+
+    #[test]
+    fn matches_non_ascii_and_escaped_keys() {
+        let text = r#"{"dependencies": {"ünï": "1.0.0", "b": "1.0.0"}}"#;
+        let text = replace(text, &["dependencies", "ünï"], "2.0.0").unwrap();
+        assert_eq!(
+            replace(&text, &["dependencies", "b"], "3.0.0").unwrap(),
+            r#"{"dependencies": {"ünï": "2.0.0", "b": "3.0.0"}}"#
+        );
+
+        let text = r#"{"dependencies": {"\u0061": "1.0.0"}}"#;
+        assert_eq!(
+            replace(text, &["dependencies", "a"], "2.0.0").unwrap(),
+            r#"{"dependencies": {"\u0061": "2.0.0"}}"#
+        );
+    }
+
+    #[test]
+    fn uses_the_last_of_repeated_keys() {
+        let text = r#"{"dependencies": {"a": "1.0.0", "a": "1.1.0"}}"#;
+        assert_eq!(
+            replace(text, &["dependencies", "a"], "2.0.0").unwrap(),
+            r#"{"dependencies": {"a": "1.0.0", "a": "2.0.0"}}"#
+        );
+    }
+
+    #[test]
+    fn fails_on_a_byte_order_mark() {
+        let text = "\u{feff}{\"dependencies\": {\"a\": \"1.0.0\"}}";
+        assert_eq!(replace(text, &["dependencies", "a"], "2.0.0"), None);
+    }
+
+    #[test]
+    fn fails_on_a_missing_key() {
+        let text = r#"{"dependencies": {"a": "1.0.0"}}"#;
+        assert_eq!(replace(text, &["dependencies", "b"], "2.0.0"), None);
+        assert_eq!(replace(text, &["devDependencies", "a"], "2.0.0"), None);
+        assert_eq!(replace("{}", &["dependencies", "a"], "2.0.0"), None);
+    }
+
+    #[test]
+    fn fails_on_a_section_which_is_not_an_object() {
+        for section in &[r#""a""#, r#"["a"]"#, "null", "1"] {
+            let text = format!(r#"{{"dependencies": {}}}"#, section);
+            assert_eq!(replace(&text, &["dependencies", "a"], "2.0.0"), None);
+        }
+    }
+
+    #[test]
+    fn finds_nested_values() {
+        let text = r#"{ "a" : { "b" : [1, {"c": 2}] , "d": true } }"#;
+        let range = find_value(text, &["a", "b"]).unwrap();
+        assert_eq!(&text[range], r#"[1, {"c": 2}]"#);
+        let range = find_value(text, &["a", "d"]).unwrap();
+        assert_eq!(&text[range], "true");
+    }
+}
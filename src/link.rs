@@ -0,0 +1,71 @@
+use crate::package::Package;
+use crate::runner::Runner;
+use anyhow::{bail, Result};
+use log::info;
+use std::path::{Path, PathBuf};
+
+/// How local dependencies are put into a dependent's node_modules, leaving its package.json alone
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkMode {
+    /// A symlink to the dependency's directory
+    Symlink,
+    /// A copy of the dependency's package.json and dist
+    Copy,
+}
+
+impl LinkMode {
+    pub fn parse(mode: &str) -> Result<LinkMode> {
+        match mode {
+            "symlink" => Ok(LinkMode::Symlink),
+            "copy" => Ok(LinkMode::Copy),
+            _ => bail!("Unknown link mode {}, expected symlink or copy", mode),
+        }
+    }
+}
+
+/// Install the package's dependencies, link the given local dependencies over the installed
+/// ones, then build it
+pub fn prepare_linked(
+    package: &dyn Package,
+    dependencies: &[&dyn Package],
+    mode: LinkMode,
+    runner: &dyn Runner,
+) -> Result<()> {
+    info!("Preparing package: {}", package.get_name());
+
+    package.install(runner)?;
+    for dependency in dependencies {
+        link(package, *dependency, mode, runner)?;
+    }
+    package.build(runner)
+}
+
+/// Put the dependency into the dependent's node_modules
+pub fn link(
+    dependent: &dyn Package,
+    dependency: &dyn Package,
+    mode: LinkMode,
+    runner: &dyn Runner,
+) -> Result<()> {
+    info!(
+        "Linking {} into {}",
+        dependency.get_name(),
+        dependent.get_name()
+    );
+    let target = get_link_path(&dependent.get_path(), &dependency.get_name());
+    let source = dependency.get_path();
+
+    match mode {
+        LinkMode::Symlink => runner.symlink(&source, &target),
+        LinkMode::Copy => {
+            runner.remove(&target)?;
+            runner.copy(&source.join("package.json"), &target.join("package.json"))?;
+            runner.copy(&source.join("dist"), &target.join("dist"))
+        }
+    }
+}
+
+/// Where the dependent's package manager installs the named dependency
+pub fn get_link_path(dependent: &Path, dependency_name: &str) -> PathBuf {
+    dependent.join("node_modules").join(dependency_name)
+}
@@ -4,19 +4,38 @@ extern crate log;
 extern crate solvent;
 extern crate tar;
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
 use app_dirs::*;
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use log::warn;
+use semver::VersionReq;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 mod cache;
+mod graph;
+mod json_edit;
+mod link;
+mod metadata;
+mod npmrc;
 mod package;
 mod package_manager;
 mod registry;
+mod runner;
+mod serve;
+mod session;
 mod state;
 mod util;
-use package::Typescript;
-use registry::Registry;
+mod watch;
+use graph::GraphFormat;
+use link::LinkMode;
+use metadata::MetadataCache;
+use package::{DependencyType, FileSpec, Package, PackageOptions, Typescript};
+use package_manager::VersionQuery;
+use registry::{Registry, ResetVersion};
+use runner::{DryRun, Runner, Shell};
+use session::{Recorder, Session};
 use state::State;
 use util::{get_path, setup_env_logger_cli};
 
@@ -28,7 +47,8 @@ const APP_INFO: AppInfo = AppInfo {
 fn main() -> Result<()> {
     let state_dir = app_dir(AppDataType::UserData, &APP_INFO, "registry")
         .expect("To be able to create app dir");
-    let mut state = State::load(state_dir.clone()).unwrap_or(State::new(state_dir));
+    let mut state =
+        State::load(state_dir.clone()).unwrap_or_else(|_| State::new(state_dir.clone()));
 
     let matches = App::new("lpm")
         .version("1.0")
@@ -43,21 +63,70 @@ fn main() -> Result<()> {
         .subcommand(
             SubCommand::with_name("add")
             .about("Add a new local package to the registry")
-            .arg_from_usage("<PATH> 'Path to package directory'"),
+            .arg_from_usage("<PATH> 'Path to package directory'")
+            .args(&package_option_args()),
+            )
+        .subcommand(
+            SubCommand::with_name("config")
+            .about("Show or edit the configuration of a registered package")
+            .arg_from_usage("<PACKAGE> 'Path, name or unique name prefix of a registered package'")
+            .args(&package_option_args()),
             )
         .subcommand(
             SubCommand::with_name("update")
             .about("Update packages to introduce new code from its registered local dependencies. If no path is given, all packages are updated.")
-            .args_from_usage("[PATH]        'Path to package directory for to update'
-                             -a, --all      'Update all packages with local versions. This is the default'"),
+            .args_from_usage("[PACKAGE]     'Path, name or unique name prefix of the package to update'
+                             -a, --all      'Update all packages with local versions. This is the default'
+                             -f, --force    'Prepare packages even if they are unchanged since their last build'
+                             -j, --jobs [N] 'Number of packages to prepare at once. Defaults to 1'
+                             -d, --dependents 'Update the packages depending on the package, instead of those it depends on'
+                             -n, --dry-run  'Show what would be rewritten and run, without doing it'")
+            .arg(dependency_type_arg())
+            .arg(link_mode_arg()),
             )
         .subcommand(
             SubCommand::with_name("reset")
-            .about("Reset packages to remotely a published version. If no path is given, all packages are updated.")
-            .args_from_usage("[PATH]                    'Path to package directory for to update'
-                             -a, --all                  'Update all packages with remote versions. This is the default'
-                             -v --version [VERSION]     'Specific vresion to update to. If none is given, the latest will be used.'
-                             -l --latest                'Use the latest available remote version. This is the default'"),
+            .about("Reset dependents of packages to the versions they used before being updated. If no path is given, all packages are reset.")
+            .args_from_usage("[PACKAGE]                 'Path, name or unique name prefix of the package to reset'
+                             -a, --all                  'Reset all packages. This is the default'
+                             -v --version [VERSION]     'Specific version to reset to, instead of the original version'
+                             -l --latest                'Use the latest available remote version, instead of the original version'
+                             -t --tag [TAG]             'Use the remote version with the dist-tag, instead of the original version'
+                             -r --range [RANGE]         'Use the newest remote version matching the semver range, instead of the original version'
+                             --linked                   'Remove packages linked into node_modules by update --link and reinstall them, instead of resetting package.json'
+                             --offline                  'Resolve remote versions from the registry documents cached by earlier resets only'
+                             -n, --dry-run              'Show what would be rewritten and run, without doing it'")
+            .arg(dependency_type_arg()),
+            )
+        .subcommand(
+            SubCommand::with_name("watch")
+            .about("Update a package, then prepare its local dependencies again as their files change, along with the packages depending on them")
+            .args_from_usage("<PACKAGE>     'Path, name or unique name prefix of the package to watch'
+                             -j, --jobs [N] 'Number of packages to prepare at once. Defaults to 1'
+                             --debounce [MS] 'Milliseconds to wait for more changes before preparing. Defaults to 300'")
+            .arg(link_mode_arg()),
+            )
+        .subcommand(
+            SubCommand::with_name("sync")
+            .about("Copy the changed build output of a package into the node_modules of the registered packages depending on it, without reinstalling them")
+            .args_from_usage("<PACKAGE>     'Path, name or unique name prefix of the package to sync'
+                             -b, --build    'Build the package before syncing it'
+                             -n, --dry-run  'Show what would be copied and run, without doing it'"),
+            )
+        .subcommand(
+            SubCommand::with_name("publish")
+            .about("Publish a package and the local packages it depends on, dependencies first, replacing local file: specs with the versions just published. If no package is given, all packages are published.")
+            .args_from_usage("[PACKAGE]      'Path, name or unique name prefix of the package to publish'
+                             -a, --all       'Publish all packages. This is the default'
+                             --restart       'Publish packages again even if an unfinished earlier run published them'
+                             -n, --dry-run   'Show what would be rewritten and run, without doing it'")
+            .arg(dependency_type_arg()),
+            )
+        .subcommand(
+            SubCommand::with_name("serve")
+            .about("Serve every registered package from a local npm registry, passing requests for other packages through to the upstream registry")
+            .args_from_usage("-p, --port [PORT]  'Port to listen on. Defaults to 4874'
+                             --upstream [URL]   'Registry to pass other requests through to. Defaults to the registry configured in .npmrc'"),
             )
         .subcommand(
             SubCommand::with_name("list")
@@ -65,87 +134,458 @@ fn main() -> Result<()> {
             .about("List currently added packages"),
             )
         .subcommand(SubCommand::with_name("clear").about("Clear current package list"))
+        .subcommand(
+            SubCommand::with_name("graph")
+            .about("Print the registered packages and the dependencies between them")
+            .arg(
+                Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .possible_values(&["dot", "mermaid", "json"])
+                .default_value("dot")
+                .help("Format to print the graph in"),
+                )
+            .arg(
+                Arg::with_name("from")
+                .long("from")
+                .value_name("PACKAGE")
+                .help("Only show this package and the packages it depends on"),
+                ),
+            )
+        .subcommand(
+            SubCommand::with_name("session")
+            .about("Record every package.json rewritten during a session, to restore them when it ends")
+            .subcommand(SubCommand::with_name("start").about("Start a new session"))
+            .subcommand(
+                SubCommand::with_name("end")
+                .alias("abort")
+                .about("End the session, restoring every package.json rewritten during it"),
+                )
+            .subcommand(SubCommand::with_name("status").about("Show the package.json files rewritten during the session")),
+            )
+        .subcommand(
+            SubCommand::with_name("test")
+            .about("Run the test command of a registered package")
+            .arg_from_usage("<PACKAGE> 'Path, name or unique name prefix of the package to test'"),
+            )
         .subcommand(
             SubCommand::with_name("bundle")
             .about("Bundle local dependencies for release of the given package")
-            .arg_from_usage("<PATH> 'Path to package directory for release'"),
+            .args_from_usage("<PACKAGE>     'Path, name or unique name prefix of the package to release'
+                             -n, --dry-run  'Show what would be written and run, without doing it'")
+            .arg(dependency_type_arg()),
             )
         .get_matches();
 
     setup_env_logger_cli(matches.occurrences_of("v"));
 
+    let session = Session::load(state_dir.clone())?;
+
+    // persist whatever was completed, even if the command failed partway through
+    let result = run(&matches, &mut state, state_dir, session);
+    state.store();
+    result
+}
+
+fn run(
+    matches: &ArgMatches,
+    state: &mut State,
+    state_dir: PathBuf,
+    session: Option<Session>,
+) -> Result<()> {
+    if let ("session", Some(session_matches)) = matches.subcommand() {
+        return run_session(session_matches, state_dir, session);
+    }
+
+    // every package.json rewritten during a session is recorded in it
+    let shell: Arc<dyn Runner> = match session {
+        Some(session) => Arc::new(Recorder::new(session)),
+        None => Arc::new(Shell),
+    };
     match matches.subcommand() {
         ("add", Some(add_matches)) => {
-            let path = get_path(add_matches.value_of("PATH").unwrap());
+            let path = get_path(add_matches.value_of("PATH").unwrap())?;
+            let name = Typescript::new(path.clone()).get_name();
+            load_registry(state, &shell)
+                .find_by_name(&name)
+                .into_iter()
+                .filter(|other| *other != path)
+                .for_each(|other| {
+                    warn!(
+                        "{} is also the name of registered package {:?}, refer to them by path",
+                        name, other
+                    )
+                });
+            set_package_options(
+                add_matches,
+                state.package_options.entry(path.clone()).or_default(),
+            )?;
             state.package_paths.insert(path);
         }
+        ("config", Some(config_matches)) => {
+            let path = load_registry(state, &shell)
+                .resolve(config_matches.value_of("PACKAGE").unwrap())?;
+
+            let options = state.package_options.entry(path.clone()).or_default();
+            set_package_options(config_matches, options)?;
+            println!("Package: {:?}", path);
+            println!("\t install: {}", options.get_install_command());
+            println!("\t build: {}", options.get_build_command());
+            println!("\t test: {}", options.get_test_command());
+            println!("\t publish: {}", options.get_publish_command());
+            println!("\t include: {:?}", options.include);
+            println!("\t exclude: {:?}", options.exclude);
+            println!(
+                "\t ignored dependencies: {:?}",
+                options.ignored_dependencies
+            );
+            println!("\t file spec: {}", options.file_spec.get_key());
+        }
+        ("test", Some(test_matches)) => {
+            let registry = load_registry(state, &shell);
+            let path = registry.resolve(test_matches.value_of("PACKAGE").unwrap())?;
+            registry.test(path)?;
+        }
         ("list", Some(_)) => {
             println!("Packages: ");
-            state.package_paths.iter().for_each(|path| {
-                println!("\t {:?}", path);
-            });
+            // packages which moved or were deleted are still listed, so they can be found
+            state
+                .package_paths
+                .iter()
+                .for_each(|path| match Typescript::read_name(path) {
+                    Some(name) => println!("\t {} {:?}", name, path),
+                    None => println!("\t (missing) {:?}", path),
+                });
+        }
+        ("graph", Some(graph_matches)) => {
+            let registry = load_registry(state, &shell);
+            let from = match graph_matches.value_of("from") {
+                Some(key) => Some(registry.resolve(key)?),
+                None => None,
+            };
+            let format = GraphFormat::parse(graph_matches.value_of("format").unwrap())?;
+            print!("{}", registry.get_graph(from.as_deref())?.render(&format));
         }
         ("clear", Some(_)) => {
             state.package_paths.clear();
+            state.package_options.clear();
+            state.cache.clear();
         }
         ("update", Some(update_matches)) => {
-            let mut registry = load_registry(&state);
+            let mut registry = load_registry_for(state, update_matches, &shell)?;
+            if let Some(mode) = update_matches.value_of("link") {
+                registry.set_link_mode(LinkMode::parse(mode)?);
+            }
+            let force = update_matches.is_present("force");
+            let jobs = get_jobs(update_matches)?;
 
-            if update_matches.is_present("all") && update_matches.is_present("PATH") {
-                panic!("Both all and package path provided. Please provide one or the other");
-            } else if update_matches.is_present("PATH") {
-                let path = get_path(update_matches.value_of("PATH").unwrap());
-                registry.update_dependencies(path);
+            if update_matches.is_present("all") && update_matches.is_present("PACKAGE") {
+                bail!("Both all and package path provided. Please provide one or the other");
+            } else if update_matches.is_present("PACKAGE") {
+                let path = registry.resolve(update_matches.value_of("PACKAGE").unwrap())?;
+                if update_matches.is_present("dependents") {
+                    registry.update_dependents(path, state, force, jobs)?;
+                } else {
+                    registry.update_dependencies(path, state, force, jobs)?;
+                }
+            } else if update_matches.is_present("dependents") {
+                bail!("Please provide the package whose dependents to update");
             } else {
-                // update all packages
-                // TODO be smarter here to avoid double-preparing
-                state.package_paths.iter().for_each(|path| {
-                    registry.update_dependencies(PathBuf::from(path));
-                });
+                registry.update_all(state, force, jobs)?;
+            }
+        }
+        ("watch", Some(watch_matches)) => {
+            let mut registry = load_registry_for(state, watch_matches, &shell)?;
+            if let Some(mode) = watch_matches.value_of("link") {
+                registry.set_link_mode(LinkMode::parse(mode)?);
             }
+            let path = registry.resolve(watch_matches.value_of("PACKAGE").unwrap())?;
+            let jobs = get_jobs(watch_matches)?;
+            let debounce = match watch_matches.value_of("debounce") {
+                Some(debounce) => debounce
+                    .parse::<u64>()
+                    .map_err(|_| anyhow!("Invalid debounce: {}", debounce))?,
+                None => 300,
+            };
+            watch::watch(
+                &mut registry,
+                path,
+                state,
+                jobs,
+                Duration::from_millis(debounce),
+            )?;
+        }
+        ("sync", Some(sync_matches)) => {
+            let registry = load_registry_for(state, sync_matches, &shell)?;
+            let path = registry.resolve(sync_matches.value_of("PACKAGE").unwrap())?;
+            registry.sync(path, sync_matches.is_present("build"))?;
+        }
+        ("publish", Some(publish_matches)) => {
+            let mut registry = load_registry_for(state, publish_matches, &shell)?;
+            if publish_matches.is_present("all") && publish_matches.is_present("PACKAGE") {
+                bail!("Both all and package path provided. Please provide one or the other");
+            }
+            let path = match publish_matches.value_of("PACKAGE") {
+                Some(package) => Some(registry.resolve(package)?),
+                None => None,
+            };
+            registry.publish(path, publish_matches.is_present("restart"), state)?;
+        }
+        ("serve", Some(serve_matches)) => {
+            let registry = load_registry(state, &shell);
+            let port = match serve_matches.value_of("port") {
+                Some(port) => port
+                    .parse::<u16>()
+                    .map_err(|_| anyhow!("Invalid port: {}", port))?,
+                None => 4874,
+            };
+            serve::serve(&registry, state, port, serve_matches.value_of("upstream"))?;
         }
         ("reset", Some(reset_matches)) => {
-            let mut registry = load_registry(&state);
-
-            if reset_matches.is_present("all") && reset_matches.is_present("PATH") {
-                panic!("Both all and package path provided. Please provide one or the other");
-            } else if reset_matches.is_present("PATH") {
-                let path = get_path(reset_matches.value_of("PATH").unwrap());
-                if reset_matches.is_present("version") {
-                    registry.reset_dependency(
-                        path,
-                        reset_matches.value_of("version").map(|v| v.to_string()),
-                    )?;
+            let mut registry = load_registry_for(state, reset_matches, &shell)?;
+            let version = get_reset_version(reset_matches)?;
+            registry.set_metadata_cache(MetadataCache::new(
+                &state_dir,
+                reset_matches.is_present("offline"),
+            ));
+            let linked = reset_matches.is_present("linked");
+            if linked && !matches!(version, ResetVersion::Original) {
+                bail!("Linked packages are reinstalled, a version cannot be provided");
+            }
+
+            if reset_matches.is_present("all") && reset_matches.is_present("PACKAGE") {
+                bail!("Both all and package path provided. Please provide one or the other");
+            } else if reset_matches.is_present("PACKAGE") {
+                let path = registry.resolve(reset_matches.value_of("PACKAGE").unwrap())?;
+                if linked {
+                    registry.unlink_dependency(path, state)?;
                 } else {
-                    registry.reset_dependency(path, None)?;
+                    registry.reset_dependency(path, &version, state)?;
                 }
             } else {
                 // update all packages
-                state.package_paths.iter().for_each(|path| {
-                    registry
-                        .reset_dependency(PathBuf::from(path), None)
-                        .expect("Unable to reset dependency");
-                });
+                let paths: Vec<PathBuf> = state.package_paths.iter().cloned().collect();
+                for path in paths {
+                    if linked {
+                        registry.unlink_dependency(path, state)?;
+                    } else {
+                        registry.reset_dependency(path, &version, state)?;
+                    }
+                }
             }
         }
         ("bundle", Some(bundle_matches)) => {
-            let mut registry = load_registry(&state);
-            let path = get_path(bundle_matches.value_of("PATH").unwrap());
-            registry.bundle_dependencies(path);
+            let mut registry = load_registry_for(state, bundle_matches, &shell)?;
+            let path = registry.resolve(bundle_matches.value_of("PACKAGE").unwrap())?;
+            registry.bundle_dependencies(path, state)?;
         }
         _ => unreachable!(),
     };
 
-    state.store();
     Ok(())
 }
 
-fn load_registry(state: &State) -> Registry {
-    let mut registry = Registry::new();
+fn run_session(matches: &ArgMatches, state_dir: PathBuf, session: Option<Session>) -> Result<()> {
+    match matches.subcommand() {
+        ("start", Some(_)) => {
+            if session.is_some() {
+                bail!("A session is already in progress, end it first");
+            }
+            Session::new(state_dir).store()?;
+            println!("Session started");
+        }
+        ("end", Some(_)) => {
+            let restored = session
+                .ok_or_else(|| anyhow!("No session in progress"))?
+                .restore()?;
+            println!("Session ended, restored {} files: ", restored.len());
+            restored.iter().for_each(|path| println!("\t {:?}", path));
+        }
+        ("status", Some(_)) => match session {
+            Some(session) => {
+                println!("Session started {}", session.get_started());
+                session
+                    .get_files()
+                    .iter()
+                    .for_each(|path| println!("\t {:?}", path));
+            }
+            None => println!("No session in progress"),
+        },
+        _ => bail!("Please provide a session subcommand: start, end or status"),
+    }
+    Ok(())
+}
+
+fn load_registry(state: &State, shell: &Arc<dyn Runner>) -> Registry {
+    load_registry_with(state, shell.clone())
+}
+
+/// Load the registry, running commands and writes with the dry run runner and rewriting only the
+/// dependency sections requested
+fn load_registry_for(
+    state: &State,
+    matches: &ArgMatches,
+    shell: &Arc<dyn Runner>,
+) -> Result<Registry> {
+    let runner: Arc<dyn Runner> = if matches.is_present("dry-run") {
+        Arc::new(DryRun)
+    } else {
+        shell.clone()
+    };
+    let mut registry = load_registry_with(state, runner);
+
+    if let Some(keys) = matches.values_of("section") {
+        let dependency_types = keys
+            .map(DependencyType::parse)
+            .collect::<Result<Vec<DependencyType>>>()?;
+        registry.set_dependency_types(dependency_types);
+    }
+    Ok(registry)
+}
+
+fn load_registry_with(state: &State, runner: Arc<dyn Runner>) -> Registry {
+    let mut registry = Registry::new(runner);
 
-    state
-        .package_paths
+    state.package_paths.iter().for_each(|path| {
+        registry.add(Typescript::with_options(
+            PathBuf::from(path),
+            state.get_package_options(path),
+        ))
+    });
+    registry
+        .find_cycles()
         .iter()
-        .for_each(|path| registry.add(Typescript::new(PathBuf::from(path))));
+        .for_each(|cycle| warn!("{}", registry.describe_cycle(cycle)));
     registry
 }
+
+fn link_mode_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("link")
+        .long("link")
+        .value_name("MODE")
+        .possible_values(&["symlink", "copy"])
+        .help("Leave package.json alone and put local dependencies into node_modules instead, as a symlink or as a copy of their package.json and dist")
+}
+
+fn get_reset_version(matches: &ArgMatches) -> Result<ResetVersion> {
+    let given = ["version", "latest", "tag", "range"]
+        .iter()
+        .filter(|name| matches.is_present(name))
+        .count();
+    if given > 1 {
+        bail!("Only one of version, latest, tag and range can be provided");
+    }
+
+    if let Some(version) = matches.value_of("version") {
+        Ok(ResetVersion::Exact(version.to_string()))
+    } else if matches.is_present("latest") {
+        Ok(ResetVersion::Published(VersionQuery::Latest))
+    } else if let Some(tag) = matches.value_of("tag") {
+        Ok(ResetVersion::Published(VersionQuery::Tag(tag.to_string())))
+    } else if let Some(range) = matches.value_of("range") {
+        let range =
+            VersionReq::parse(range).with_context(|| format!("Invalid semver range: {}", range))?;
+        Ok(ResetVersion::Published(VersionQuery::Range(range)))
+    } else {
+        Ok(ResetVersion::Original)
+    }
+}
+
+fn get_jobs(matches: &ArgMatches) -> Result<usize> {
+    match matches.value_of("jobs") {
+        Some(jobs) => jobs
+            .parse::<usize>()
+            .ok()
+            .filter(|jobs| *jobs > 0)
+            .ok_or_else(|| anyhow!("Invalid number of jobs: {}", jobs)),
+        None => Ok(1),
+    }
+}
+
+fn dependency_type_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("section")
+        .short("s")
+        .long("section")
+        .value_name("SECTION")
+        .possible_values(&[
+            "dependencies",
+            "devDependencies",
+            "peerDependencies",
+            "optionalDependencies",
+        ])
+        .multiple(true)
+        .number_of_values(1)
+        .help("Only rewrite dependencies listed in this package.json section. May be given multiple times. Defaults to all sections")
+}
+
+fn package_option_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("install-command")
+            .long("install-command")
+            .value_name("COMMAND")
+            .help("Command installing the package's dependencies, run in its directory. Defaults to `npm install`, empty to skip"),
+        Arg::with_name("build-command")
+            .long("build-command")
+            .value_name("COMMAND")
+            .help("Command building the package, run in its directory. Defaults to `npm run build`, empty to skip"),
+        Arg::with_name("test-command")
+            .long("test-command")
+            .value_name("COMMAND")
+            .help("Command testing the package, run in its directory. Defaults to `npm test`, empty to skip"),
+        Arg::with_name("publish-command")
+            .long("publish-command")
+            .value_name("COMMAND")
+            .help("Command publishing the package, run in its directory. Defaults to `npm publish`, empty to skip"),
+        Arg::with_name("include")
+            .long("include")
+            .value_name("GLOB")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Only hash files matching this glob to detect changes. May be given multiple times"),
+        Arg::with_name("exclude")
+            .long("exclude")
+            .value_name("GLOB")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Do not hash files matching this glob to detect changes. May be given multiple times"),
+        Arg::with_name("ignore-dependency")
+            .long("ignore-dependency")
+            .value_name("NAME")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Treat the registered package with this name as unrelated, to break a dependency cycle. May be given multiple times"),
+        Arg::with_name("file-spec")
+            .long("file-spec")
+            .value_name("FORM")
+            .possible_values(&["absolute", "relative", "home"])
+            .help("How the package refers to local packages: by absolute path, by path relative to the package, or by path under the home directory as ~/... Defaults to absolute"),
+    ]
+}
+
+fn set_package_options(matches: &ArgMatches, options: &mut PackageOptions) -> Result<()> {
+    if let Some(command) = matches.value_of("install-command") {
+        options.install_command = Some(command.to_string());
+    }
+    if let Some(command) = matches.value_of("build-command") {
+        options.build_command = Some(command.to_string());
+    }
+    if let Some(command) = matches.value_of("test-command") {
+        options.test_command = Some(command.to_string());
+    }
+    if let Some(command) = matches.value_of("publish-command") {
+        options.publish_command = Some(command.to_string());
+    }
+    if let Some(globs) = matches.values_of("include") {
+        options.include = globs.map(String::from).collect();
+    }
+    if let Some(globs) = matches.values_of("exclude") {
+        options.exclude = globs.map(String::from).collect();
+    }
+    if let Some(names) = matches.values_of("ignore-dependency") {
+        options.ignored_dependencies = names.map(String::from).collect();
+    }
+    if let Some(file_spec) = matches.value_of("file-spec") {
+        options.file_spec = FileSpec::parse(file_spec)?;
+    }
+    Ok(())
+}
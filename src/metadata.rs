@@ -0,0 +1,61 @@
+use anyhow::{Context, Result};
+use blake2::{Blake2b, Digest};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Registry documents fetched before, stored by URL in a directory next to the state file
+#[derive(Clone, Debug)]
+pub struct MetadataCache {
+    dir: PathBuf,
+    offline: bool,
+}
+
+/// A registry document and the ETag it was served with
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CachedDocument {
+    pub etag: Option<String>,
+    pub body: String,
+}
+
+impl MetadataCache {
+    /// The cache in the state directory. When offline, documents are only read from the cache
+    pub fn new(state_dir: &Path, offline: bool) -> MetadataCache {
+        MetadataCache {
+            dir: state_dir.join("metadata"),
+            offline,
+        }
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    /// The document last fetched from the URL, if any
+    pub fn get(&self, url: &str) -> Option<CachedDocument> {
+        let file = self.get_file(url);
+        let contents = fs::read(&file).ok()?;
+        match serde_json::from_slice(&contents) {
+            Ok(document) => Some(document),
+            Err(e) => {
+                debug!("Ignoring unreadable cached document {:?}: {}", file, e);
+                None
+            }
+        }
+    }
+
+    pub fn store(&self, url: &str, document: &CachedDocument) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Unable to create metadata cache {:?}", self.dir))?;
+        let file = self.get_file(url);
+        debug!("Caching {} in {:?}", url, file);
+        fs::write(&file, serde_json::to_string(document)?)
+            .with_context(|| format!("Unable to write cached document {:?}", file))
+    }
+
+    fn get_file(&self, url: &str) -> PathBuf {
+        let name = hex::encode(Blake2b::digest(url.as_bytes()));
+        self.dir.join(format!("{}.json", &name[..32]))
+    }
+}
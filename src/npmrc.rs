@@ -0,0 +1,159 @@
+use anyhow::{Context, Result};
+use log::debug;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_REGISTRY: &str = "https://registry.npmjs.org/";
+
+/// npm configuration read from `.npmrc` files
+#[derive(Debug, Default)]
+pub struct NpmConfig {
+    values: HashMap<String, String>,
+}
+
+impl NpmConfig {
+    /// Read the `.npmrc` of the project in the given directory, the user's and the global one.
+    /// Settings in the project's take precedence over the user's, which take precedence over the
+    /// global ones
+    pub fn load(project_dir: &Path) -> Result<NpmConfig> {
+        let mut config = NpmConfig::default();
+        for file in NpmConfig::get_config_files(project_dir) {
+            if file.is_file() {
+                debug!("Reading npm config {:?}", file);
+                let contents = fs::read_to_string(&file)
+                    .with_context(|| format!("Unable to read npm config {:?}", file))?;
+                config.merge_missing(&contents);
+            }
+        }
+        Ok(config)
+    }
+
+    /// The configuration set by the `.npmrc` contents alone
+    #[cfg(test)]
+    pub fn parse(contents: &str) -> NpmConfig {
+        let mut config = NpmConfig::default();
+        config.merge_missing(contents);
+        config
+    }
+
+    /// Add the settings of the `.npmrc` contents which are not already set
+    fn merge_missing(&mut self, contents: &str) {
+        for (key, value) in parse_settings(contents) {
+            self.values.entry(key).or_insert(value);
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    /// The URL of the registry serving the package, always ending with a slash
+    pub fn get_registry(&self, package: &str) -> String {
+        self.get_scope_registry(package)
+            .unwrap_or_else(|| self.get_default_registry())
+    }
+
+    /// The URL of the registry configured for the scope of the package, if it is scoped and one
+    /// is configured, always ending with a slash
+    pub fn get_scope_registry(&self, package: &str) -> Option<String> {
+        package
+            .split('/')
+            .next()
+            .filter(|scope| scope.starts_with('@') && package.contains('/'))
+            .and_then(|scope| self.get(&format!("{}:registry", scope)))
+            .map(with_trailing_slash)
+    }
+
+    /// The URL of the registry serving unscoped packages, always ending with a slash
+    pub fn get_default_registry(&self) -> String {
+        with_trailing_slash(self.get("registry").unwrap_or(DEFAULT_REGISTRY))
+    }
+
+    /// The token authenticating requests to the registry. Tokens are matched by registry URL
+    /// without its protocol, as in `//registry.npmjs.org/:_authToken`, trying the full path
+    /// before each of its parents. A token without a URL applies to the default registry
+    pub fn get_auth_token(&self, registry: &str) -> Option<&str> {
+        let mut nerf_dart = registry
+            .split_once("//")
+            .map(|(_, rest)| format!("//{}", rest))?;
+
+        loop {
+            if let Some(token) = self.get(&format!("{}:_authToken", nerf_dart)) {
+                return Some(token);
+            }
+            // drop the last path segment, keeping the trailing slash
+            let trimmed = nerf_dart.trim_end_matches('/');
+            match trimmed.rfind('/') {
+                Some(index) if index > 1 => nerf_dart = trimmed[..=index].to_string(),
+                _ => break,
+            }
+        }
+
+        if self.get_default_registry() == registry {
+            return self.get("_authToken");
+        }
+        None
+    }
+
+    fn get_config_files(project_dir: &Path) -> Vec<PathBuf> {
+        let user = env::var_os("NPM_CONFIG_USERCONFIG")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".npmrc")));
+        let global = env::var_os("NPM_CONFIG_GLOBALCONFIG")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| {
+                let prefix = env::var_os("NPM_CONFIG_PREFIX")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| PathBuf::from("/usr/local"));
+                prefix.join("etc").join("npmrc")
+            });
+
+        let mut files = vec![project_dir.join(".npmrc")];
+        files.extend(user);
+        files.push(global);
+        files
+    }
+}
+
+fn with_trailing_slash(url: &str) -> String {
+    if url.ends_with('/') {
+        url.to_string()
+    } else {
+        format!("{}/", url)
+    }
+}
+
+/// The settings in the `.npmrc` contents, with `${VAR}` references replaced by the environment
+/// variable's value
+fn parse_settings(contents: &str) -> Vec<(String, String)> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with(';'))
+        .filter_map(|line| {
+            let (key, value) = line.split_once('=')?;
+            let value = value.trim().trim_matches('"');
+            Some((expand_env(key.trim()), expand_env(value)))
+        })
+        .collect()
+}
+
+fn expand_env(value: &str) -> String {
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        match rest[start..].find('}') {
+            Some(end) => {
+                expanded.push_str(&rest[..start]);
+                let name = &rest[start + 2..start + end];
+                expanded.push_str(&env::var(name).unwrap_or_default());
+                rest = &rest[start + end + 1..];
+            }
+            None => break,
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
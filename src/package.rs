@@ -1,118 +1,425 @@
-use crate::package_manager::{Npm, PackageManager};
-use crate::util::run_basic_command_expect;
-use anyhow::{anyhow, Result};
+use crate::json_edit;
+use crate::runner::Runner;
+use crate::util::{get_home_dir, get_relative_path};
+use anyhow::{anyhow, Context, Result};
 use flate2::write::GzEncoder;
 use flate2::Compression;
-use log::{debug, error, info};
+use log::{debug, info, trace, warn};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::{fs, fs::File, io::Write, path::PathBuf};
-
-pub trait Package {
-    fn prepare(&self);
+use std::{
+    fs,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+pub trait Package: Send + Sync {
+    fn prepare(&self, runner: &dyn Runner) -> Result<()>;
+    /// Install the package's dependencies, the first step of preparing it
+    fn install(&self, runner: &dyn Runner) -> Result<()>;
+    /// Build the package, the last step of preparing it
+    fn build(&self, runner: &dyn Runner) -> Result<()>;
+    fn test(&self, runner: &dyn Runner) -> Result<()>;
+    /// Publish the package to its registry
+    fn publish(&self, runner: &dyn Runner) -> Result<()>;
     fn get_name(&self) -> String;
+    /// The version in package.json
+    fn get_version(&self) -> Result<String>;
     fn get_path(&self) -> PathBuf;
-    fn get_version_value(&self) -> String;
-    fn reset(&mut self, dependency_name: String, version: Option<String>) -> Result<()>;
-    fn update(&mut self, dependency: Box<dyn Package>) -> bool;
-    fn depends_on(&self, dependency_name: &str) -> bool;
+    fn get_options(&self) -> &PackageOptions;
+    /// The spec pointing the dependent package in the given directory at this package
+    fn get_version_value(&self, dependent: &Path, file_spec: FileSpec) -> String;
+    fn reset(
+        &mut self,
+        dependency_type: DependencyType,
+        dependency_name: String,
+        version: String,
+        runner: &dyn Runner,
+    ) -> Result<()>;
+    /// Point the package at the given local dependency in each of the given sections it is
+    /// listed in, returning those sections with the spec replaced in each
+    fn update(
+        &mut self,
+        dependency: Box<dyn Package>,
+        dependency_types: &[DependencyType],
+        runner: &dyn Runner,
+    ) -> Result<Vec<(DependencyType, String)>>;
+    /// The sections of package.json listing the dependency
+    fn get_dependency_types(&self, dependency_name: &str) -> Vec<DependencyType>;
+    fn get_dependency_spec(
+        &self,
+        dependency_type: DependencyType,
+        dependency_name: &str,
+    ) -> Option<String>;
+
+    fn depends_on(&self, dependency_name: &str) -> bool {
+        !self.get_dependency_types(dependency_name).is_empty()
+    }
+}
+
+/// A section of package.json listing dependencies
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub enum DependencyType {
+    Dependencies,
+    DevDependencies,
+    PeerDependencies,
+    OptionalDependencies,
+}
+
+impl DependencyType {
+    pub const ALL: [DependencyType; 4] = [
+        DependencyType::Dependencies,
+        DependencyType::DevDependencies,
+        DependencyType::PeerDependencies,
+        DependencyType::OptionalDependencies,
+    ];
+
+    /// The key of the section in package.json
+    pub fn get_key(&self) -> &'static str {
+        match self {
+            DependencyType::Dependencies => "dependencies",
+            DependencyType::DevDependencies => "devDependencies",
+            DependencyType::PeerDependencies => "peerDependencies",
+            DependencyType::OptionalDependencies => "optionalDependencies",
+        }
+    }
+
+    pub fn parse(key: &str) -> Result<DependencyType> {
+        DependencyType::ALL
+            .iter()
+            .find(|dependency_type| dependency_type.get_key() == key)
+            .copied()
+            .ok_or_else(|| anyhow!("Unknown dependency section {}", key))
+    }
+}
+
+/// User configuration for a registered package
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PackageOptions {
+    /// Globs restricting which files are hashed to detect changes
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Globs of files which are not hashed to detect changes
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Command installing the package's dependencies. Empty to skip installing
+    #[serde(default)]
+    pub install_command: Option<String>,
+    /// Command building the package. Empty to skip building
+    #[serde(default)]
+    pub build_command: Option<String>,
+    /// Command testing the package. Empty to skip testing
+    #[serde(default)]
+    pub test_command: Option<String>,
+    /// Command publishing the package. Empty to skip publishing
+    #[serde(default)]
+    pub publish_command: Option<String>,
+    /// Names of registered packages this package depends on which lpm treats as unrelated, to
+    /// break dependency cycles
+    #[serde(default)]
+    pub ignored_dependencies: Vec<String>,
+    /// How the package refers to the local packages it depends on
+    #[serde(default)]
+    pub file_spec: FileSpec,
+}
+
+/// The form of the `file:` specs pointing a package at local packages
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileSpec {
+    /// The absolute path of the local package
+    #[default]
+    Absolute,
+    /// The path from the package to the local package
+    Relative,
+    /// The path from the home directory as `~/...` for local packages under it, else absolute
+    Home,
+}
+
+impl FileSpec {
+    pub fn get_key(&self) -> &'static str {
+        match self {
+            FileSpec::Absolute => "absolute",
+            FileSpec::Relative => "relative",
+            FileSpec::Home => "home",
+        }
+    }
+
+    pub fn parse(key: &str) -> Result<FileSpec> {
+        match key {
+            "absolute" => Ok(FileSpec::Absolute),
+            "relative" => Ok(FileSpec::Relative),
+            "home" => Ok(FileSpec::Home),
+            _ => Err(anyhow!(
+                "Unknown file spec {}, expected absolute, relative or home",
+                key
+            )),
+        }
+    }
+}
+
+impl PackageOptions {
+    pub fn get_install_command(&self) -> &str {
+        self.install_command.as_deref().unwrap_or("npm install")
+    }
+
+    pub fn get_build_command(&self) -> &str {
+        self.build_command.as_deref().unwrap_or("npm run build")
+    }
+
+    pub fn get_test_command(&self) -> &str {
+        self.test_command.as_deref().unwrap_or("npm test")
+    }
+
+    pub fn get_publish_command(&self) -> &str {
+        self.publish_command.as_deref().unwrap_or("npm publish")
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Typescript {
     package_json: PackageJson,
     path: PathBuf,
+    options: PackageOptions,
 }
 
 impl Typescript {
     pub fn new(path: PathBuf) -> Typescript {
+        Typescript::with_options(path, PackageOptions::default())
+    }
+
+    pub fn with_options(path: PathBuf, options: PackageOptions) -> Typescript {
         let mut package_json_path = path.clone();
         package_json_path.push("package.json");
         let package_json = PackageJson::new(package_json_path).expect("to work");
 
-        Typescript { package_json, path }
+        Typescript {
+            package_json,
+            path,
+            options,
+        }
+    }
+
+    /// The name in the package.json of the package at the path, if it can be read
+    pub fn read_name(path: &Path) -> Option<String> {
+        let text = fs::read_to_string(path.join("package.json")).ok()?;
+        let data: Value = serde_json::from_str(&text).ok()?;
+        data["name"].as_str().map(String::from)
+    }
+
+    /// Show a dependency spec change as part of a dry run's plan
+    fn plan_spec_change(
+        &self,
+        runner: &dyn Runner,
+        dependency_type: DependencyType,
+        dependency_name: &str,
+        from: &str,
+        to: &str,
+    ) {
+        if runner.is_dry_run() && from != to {
+            println!(
+                "{}: change {} {} from {} to {}",
+                self.get_name(),
+                dependency_type.get_key(),
+                dependency_name,
+                from,
+                to
+            );
+        }
+    }
+
+    fn run_step(&self, runner: &dyn Runner, step: &str, command: &str) -> Result<()> {
+        if command.trim().is_empty() {
+            debug!("No {} command for {}, skipping", step, self.get_name());
+            return Ok(());
+        }
+
+        runner.run_checked(
+            command,
+            &self.path,
+            &self.get_name(),
+            format!("Failed to {} {}", step, self.get_name()).as_str(),
+        )?;
+        Ok(())
     }
 }
 
 impl Package for Typescript {
-    fn prepare(&self) {
+    fn prepare(&self, runner: &dyn Runner) -> Result<()> {
         info!("Preparing package: {}", self.get_name());
 
-        run_basic_command_expect(
-            format!("npm install --prefix={:?}", self.path).as_str(),
-            "Failed to install",
-        );
+        self.install(runner)?;
+        self.build(runner)
+    }
 
-        run_basic_command_expect(
-            format!("npm run build --prefix={:?}", self.path).as_str(),
-            "Failed to build",
-        );
+    fn install(&self, runner: &dyn Runner) -> Result<()> {
+        self.run_step(runner, "install", self.options.get_install_command())
+    }
+
+    fn build(&self, runner: &dyn Runner) -> Result<()> {
+        self.run_step(runner, "build", self.options.get_build_command())
+    }
+
+    fn test(&self, runner: &dyn Runner) -> Result<()> {
+        info!("Testing package: {}", self.get_name());
+
+        self.run_step(runner, "test", self.options.get_test_command())
+    }
+
+    fn publish(&self, runner: &dyn Runner) -> Result<()> {
+        info!("Publishing package: {}", self.get_name());
+
+        self.run_step(runner, "publish", self.options.get_publish_command())
     }
 
     fn get_name(&self) -> String {
         self.package_json.name.clone()
     }
 
+    fn get_version(&self) -> Result<String> {
+        self.package_json.data["version"]
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| anyhow!("No version in package.json of {}", self.get_name()))
+    }
+
     fn get_path(&self) -> PathBuf {
         self.path.clone()
     }
 
-    fn get_version_value(&self) -> String {
-        format!("file:{}", self.path.to_string_lossy())
+    fn get_options(&self) -> &PackageOptions {
+        &self.options
+    }
+
+    fn get_version_value(&self, dependent: &Path, file_spec: FileSpec) -> String {
+        let path = match file_spec {
+            FileSpec::Absolute => self.path.clone(),
+            FileSpec::Relative => get_relative_path(dependent, &self.path),
+            FileSpec::Home => match get_home_dir()
+                .and_then(|home| self.path.strip_prefix(home).ok().map(Path::to_path_buf))
+            {
+                Some(under_home) => Path::new("~").join(under_home),
+                None => self.path.clone(),
+            },
+        };
+        format!("file:{}", path.to_string_lossy())
     }
 
-    fn reset(&mut self, dependency_name: String, version: Option<String>) -> Result<()> {
-        let version_string = version.unwrap_or(Npm::get_latest_version_value(&dependency_name)?);
+    fn reset(
+        &mut self,
+        dependency_type: DependencyType,
+        dependency_name: String,
+        version: String,
+        runner: &dyn Runner,
+    ) -> Result<()> {
         info!(
-            "Resetting dependency {} to version {} in {}",
+            "Resetting {} {} to version {} in {}",
+            dependency_type.get_key(),
             &dependency_name,
-            version_string,
+            version,
             self.get_name()
         );
 
-        if self.package_json.update(&dependency_name, &version_string) {
-            self.package_json.write()?;
-            Ok(())
-        } else {
-            Err(anyhow!("Could not update package.json"))
+        match self
+            .package_json
+            .update(dependency_type, &dependency_name, &version)
+        {
+            Some(previous) => {
+                self.plan_spec_change(
+                    runner,
+                    dependency_type,
+                    &dependency_name,
+                    &previous,
+                    &version,
+                );
+                self.package_json.write(runner)
+            }
+            None => Err(anyhow!("Could not update package.json")),
         }
     }
 
-    fn update(&mut self, dependency: Box<dyn Package>) -> bool {
+    fn update(
+        &mut self,
+        dependency: Box<dyn Package>,
+        dependency_types: &[DependencyType],
+        runner: &dyn Runner,
+    ) -> Result<Vec<(DependencyType, String)>> {
         info!(
             "Updating dependency {:?} for {:?}",
             dependency.get_name(),
             self.get_name()
         );
-        if self
-            .package_json
-            .update(&dependency.get_name(), &dependency.get_version_value())
-        {
-            self.package_json.write().map_or_else(
-                |e| {
-                    error!("Received error writing package.json: {:?}", e);
-                    false
-                },
-                |_| true,
-            )
-        } else {
-            false
+        let version = dependency.get_version_value(&self.path, self.options.file_spec);
+        let mut replaced = Vec::new();
+
+        for dependency_type in dependency_types {
+            if let Some(previous) =
+                self.package_json
+                    .update(*dependency_type, &dependency.get_name(), &version)
+            {
+                self.plan_spec_change(
+                    runner,
+                    *dependency_type,
+                    &dependency.get_name(),
+                    &previous,
+                    &version,
+                );
+                replaced.push((*dependency_type, previous));
+            }
+        }
+
+        if !replaced.is_empty() {
+            self.package_json
+                .write(runner)
+                .with_context(|| format!("Unable to write package.json of {}", self.get_name()))?;
         }
+        Ok(replaced)
     }
 
-    fn depends_on(&self, dependency_name: &str) -> bool {
+    fn get_dependency_types(&self, dependency_name: &str) -> Vec<DependencyType> {
+        DependencyType::ALL
+            .iter()
+            .filter(|dependency_type| {
+                self.package_json
+                    .get(**dependency_type, dependency_name)
+                    .is_some()
+            })
+            .copied()
+            .collect()
+    }
+
+    fn get_dependency_spec(
+        &self,
+        dependency_type: DependencyType,
+        dependency_name: &str,
+    ) -> Option<String> {
         self.package_json
-            .get(dependency_name)
-            .map_or(false, |_| true)
+            .get(dependency_type, dependency_name)
+            .map(String::from)
     }
 }
 
 pub struct Bundle {
     inner: Box<dyn Package>,
+    /// package.json contents to bundle instead of the package's own
+    manifest: Option<Value>,
 }
 
 impl Bundle {
     pub fn new(inner: Box<dyn Package>) -> Bundle {
-        Bundle { inner }
+        Bundle {
+            inner,
+            manifest: None,
+        }
+    }
+
+    /// Bundle the package with the given package.json contents instead of its own
+    pub fn with_manifest(inner: Box<dyn Package>, manifest: Value) -> Bundle {
+        Bundle {
+            inner,
+            manifest: Some(manifest),
+        }
     }
 
     pub fn get_tarball_file(&self) -> String {
@@ -122,56 +429,117 @@ impl Bundle {
     pub fn get_local_bundle_file(&self) -> String {
         format!(".lpm/{}/build.tar.gz", self.get_name())
     }
-
-    fn get_tarball_dir(&self) -> String {
-        format!("/tmp/lpm/{}", self.get_name())
-    }
 }
 
 impl Package for Bundle {
-    fn prepare(&self) {
+    fn prepare(&self, runner: &dyn Runner) -> Result<()> {
         info!("Creating tarball bundle of {}", self.get_name());
-        fs::create_dir_all(self.get_tarball_dir()).expect("Unable to create tmp dir");
-        let tarball = File::create(self.get_tarball_file()).expect("Unable to create tarball");
-        let enc = GzEncoder::new(tarball, Compression::default());
+        let tarball_file = PathBuf::from(self.get_tarball_file());
+        if runner.is_dry_run() {
+            return runner.write(&tarball_file, &[]);
+        }
+
+        let enc = GzEncoder::new(Vec::new(), Compression::default());
         let mut tar = tar::Builder::new(enc);
 
-        let mut dist = self.get_path().clone();
+        let mut dist = self.get_path();
         dist.push("dist");
         tar.append_dir_all("package/dist", dist)
-            .expect("Unable to create tar archive");
+            .with_context(|| format!("Unable to create tar archive for {}", self.get_name()))?;
+
+        match &self.manifest {
+            Some(manifest) => {
+                let contents = serde_json::to_vec_pretty(manifest)?;
+                let mut header = tar::Header::new_gnu();
+                header.set_size(contents.len() as u64);
+                header.set_mode(0o644);
+                header.set_cksum();
+                tar.append_data(&mut header, "package/package.json", &contents[..])
+            }
+            None => {
+                let mut package_json = self.get_path();
+                package_json.push("package.json");
+                tar.append_file(
+                    "package/package.json",
+                    &mut File::open(package_json).context("to access package.json")?,
+                )
+            }
+        }
+        .context("Unable to add package.json to tar archive")?;
+        let tarball = tar.into_inner()?.finish()?;
+
+        runner
+            .write(&tarball_file, &tarball)
+            .context("Unable to create tarball")
+    }
+
+    fn install(&self, runner: &dyn Runner) -> Result<()> {
+        self.inner.install(runner)
+    }
+
+    fn build(&self, runner: &dyn Runner) -> Result<()> {
+        self.inner.build(runner)
+    }
+
+    fn test(&self, runner: &dyn Runner) -> Result<()> {
+        self.inner.test(runner)
+    }
 
-        let mut package_json = self.get_path().clone();
-        package_json.push("package.json");
-        tar.append_file(
-            "package/package.json",
-            &mut File::open(package_json).expect("to access package.json"),
-        )
-        .expect("Unable to add package.json to tar archive ");
+    fn publish(&self, runner: &dyn Runner) -> Result<()> {
+        self.inner.publish(runner)
     }
 
     fn get_name(&self) -> String {
         self.inner.get_name()
     }
 
+    fn get_version(&self) -> Result<String> {
+        self.inner.get_version()
+    }
+
     fn get_path(&self) -> PathBuf {
         self.inner.get_path()
     }
 
-    fn get_version_value(&self) -> String {
+    fn get_options(&self) -> &PackageOptions {
+        self.inner.get_options()
+    }
+
+    fn get_version_value(&self, _dependent: &Path, _file_spec: FileSpec) -> String {
         format!("file:{}", self.get_local_bundle_file())
     }
 
-    fn reset(&mut self, dependency_name: String, version: Option<String>) -> Result<()> {
-        self.inner.reset(dependency_name, version)
+    fn reset(
+        &mut self,
+        dependency_type: DependencyType,
+        dependency_name: String,
+        version: String,
+        runner: &dyn Runner,
+    ) -> Result<()> {
+        self.inner
+            .reset(dependency_type, dependency_name, version, runner)
     }
 
-    fn update(&mut self, dependency: Box<dyn Package>) -> bool {
-        self.inner.update(dependency)
+    fn update(
+        &mut self,
+        dependency: Box<dyn Package>,
+        dependency_types: &[DependencyType],
+        runner: &dyn Runner,
+    ) -> Result<Vec<(DependencyType, String)>> {
+        self.inner.update(dependency, dependency_types, runner)
     }
 
-    fn depends_on(&self, dependency_name: &str) -> bool {
-        self.inner.depends_on(dependency_name)
+    fn get_dependency_types(&self, dependency_name: &str) -> Vec<DependencyType> {
+        self.inner.get_dependency_types(dependency_name)
+    }
+
+    fn get_dependency_spec(
+        &self,
+        dependency_type: DependencyType,
+        dependency_name: &str,
+    ) -> Option<String> {
+        self.inner
+            .get_dependency_spec(dependency_type, dependency_name)
     }
 }
 
@@ -180,64 +548,90 @@ struct PackageJson {
     path: PathBuf,
     name: String,
     data: Value,
+    /// The file as written, edited in place so its formatting is kept
+    text: String,
 }
 
 impl PackageJson {
     pub fn new(path: PathBuf) -> Result<PackageJson> {
         debug!("Fetching package.json from {:?}", path);
-        let data: Value = serde_json::from_reader(File::open(path.clone())?)?;
+        let text = fs::read_to_string(&path)?;
+        let data: Value = serde_json::from_str(&text)?;
         Ok(PackageJson {
-            path: path,
+            path,
             name: data
                 .get("name")
                 .expect("Package.json to have a name")
                 .to_string()
                 .replace("\"", ""),
             data,
+            text,
         })
     }
 
-    fn get_mut(&mut self, package_name: &str) -> Option<&mut String> {
+    fn get_mut(
+        &mut self,
+        dependency_type: DependencyType,
+        package_name: &str,
+    ) -> Option<&mut String> {
         self.data
-            .get_mut("dependencies")
-            .map(|dependencies| dependencies.get_mut(package_name))
-            .map_or(None, |dependency| match dependency {
+            .get_mut(dependency_type.get_key())
+            .and_then(|dependencies| match dependencies.get_mut(package_name) {
                 Some(Value::String(value)) => Some(value),
                 _ => {
-                    debug!("Package not a dependency: {:?}", package_name);
+                    trace!(
+                        "Package not in {}: {:?}",
+                        dependency_type.get_key(),
+                        package_name
+                    );
                     None
                 }
             })
     }
 
-    pub fn get(&self, package_name: &str) -> Option<&str> {
+    pub fn get(&self, dependency_type: DependencyType, package_name: &str) -> Option<&str> {
         self.data
-            .get("dependencies")
-            .map(|dependencies| dependencies.get(package_name))
-            .map_or(None, |dependency| match dependency {
-                Some(Value::String(value)) => Some(value),
+            .get(dependency_type.get_key())
+            .and_then(|dependencies| match dependencies.get(package_name) {
+                Some(Value::String(value)) => Some(value.as_str()),
                 _ => {
-                    debug!("Package not a dependency: {:?}", package_name);
+                    trace!(
+                        "Package not in {}: {:?}",
+                        dependency_type.get_key(),
+                        package_name
+                    );
                     None
                 }
             })
     }
 
-    pub fn update(&mut self, package_name: &str, new_value: &str) -> bool {
-        self.get_mut(package_name).map_or(false, |value| {
+    /// Set the spec of the dependency in the section, returning its previous spec if the section
+    /// lists it
+    pub fn update(
+        &mut self,
+        dependency_type: DependencyType,
+        package_name: &str,
+        new_value: &str,
+    ) -> Option<String> {
+        let previous = self.get_mut(dependency_type, package_name).map(|value| {
             debug!(
                 "{} -- Previous value: {}, New value: {}",
                 package_name, value, new_value
             );
-            *value = new_value.to_string();
-            true
-        })
+            std::mem::replace(value, new_value.to_string())
+        })?;
+
+        let key_path = [dependency_type.get_key(), package_name];
+        if !json_edit::replace_value(&mut self.text, &key_path, &Value::from(new_value)) {
+            warn!("Unable to edit {:?} in place, reformatting it", self.path);
+            self.text = format!("{}\n", serde_json::to_string_pretty(&self.data).unwrap());
+        }
+        Some(previous)
     }
 
-    pub fn write(&self) -> std::io::Result<()> {
+    pub fn write(&self, runner: &dyn Runner) -> Result<()> {
+        runner.record_original(&self.path)?;
         debug!("Writing package.json to {:?}", self.path);
-        let output = serde_json::to_string_pretty(&self.data)?;
-        let mut file = File::create(self.path.clone())?;
-        file.write_all(&output.as_bytes())
+        runner.write(&self.path, self.text.as_bytes())
     }
 }
@@ -1,49 +1,499 @@
-use std::cmp;
-use anyhow::Result;
-use log::info;
+use crate::metadata::{CachedDocument, MetadataCache};
+use crate::npmrc::NpmConfig;
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+use reqwest::blocking::Client;
+use reqwest::header::{ACCEPT, ETAG, IF_NONE_MATCH};
+use reqwest::StatusCode;
+use semver::{Version, VersionReq};
+use serde::de::IgnoredAny;
 use serde::Deserialize;
-use semver::Version;
+use std::cmp;
+use std::collections::HashMap;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
 
-pub trait PackageManager {
-    fn get_latest_version_value(package: &str) -> Result<String>;
+/// Asks for the abbreviated package document, which is much smaller than the full one
+const ABBREVIATED_METADATA: &str =
+    "application/vnd.npm.install-v1+json; q=1.0, application/json; q=0.8, */*";
+
+/// Which of the published versions of a package to use
+pub enum VersionQuery {
+    /// The newer of the versions tagged latest and rc
+    Latest,
+    /// The version with the given dist-tag
+    Tag(String),
+    /// The newest version matching the semver range
+    Range(VersionReq),
 }
 
-#[derive(Deserialize)]
-struct Tags {
-    latest: Option<String>,
-    rc: Option<String>,
+pub trait PackageManager {
+    fn get_version_value(&self, package: &str, query: &VersionQuery) -> Result<String>;
 }
 
 #[derive(Deserialize)]
 struct NpmRegistryObject {
-    #[serde(rename = "dist-tags")]
-    tags: Tags,
+    #[serde(rename = "dist-tags", default)]
+    tags: HashMap<String, String>,
+    #[serde(default)]
+    versions: HashMap<String, IgnoredAny>,
+}
+
+/// Looks packages up in the registries configured by the `.npmrc` files of a project
+pub struct Npm {
+    config: NpmConfig,
+    metadata_cache: Option<MetadataCache>,
 }
 
-pub struct Npm;
+impl Npm {
+    pub fn new(project_dir: &Path, metadata_cache: Option<MetadataCache>) -> Result<Npm> {
+        Ok(Npm::with_config(
+            NpmConfig::load(project_dir)?,
+            metadata_cache,
+        ))
+    }
+
+    pub fn with_config(config: NpmConfig, metadata_cache: Option<MetadataCache>) -> Npm {
+        Npm {
+            config,
+            metadata_cache,
+        }
+    }
+
+    /// The URL of the registry the package is looked up in
+    pub fn get_registry(&self, package: &str) -> String {
+        self.config.get_registry(package)
+    }
+
+    /// The URL of the package's document in its registry
+    pub fn get_package_url(&self, package: &str) -> String {
+        format!("{}{}", self.get_registry(package), encode_name(package))
+    }
+
+    /// The package's document, revalidating the cached one if there is a metadata cache, or only
+    /// reading it from the cache when offline
+    fn fetch_document(&self, package: &str) -> Result<String> {
+        let url = self.get_package_url(package);
+        let cache = match &self.metadata_cache {
+            Some(cache) => cache,
+            None => return Ok(self.request_document(package, &url, None)?.unwrap().body),
+        };
+
+        let cached = cache.get(&url);
+        if cache.is_offline() {
+            return cached.map(|document| document.body).ok_or_else(|| {
+                anyhow!(
+                    "{} is not in the metadata cache, fetch it once without --offline",
+                    package
+                )
+            });
+        }
+
+        let etag = cached.as_ref().and_then(|document| document.etag.clone());
+        match self.request_document(package, &url, etag.as_deref())? {
+            Some(document) => {
+                if let Err(e) = cache.store(&url, &document) {
+                    warn!("{:?}", e);
+                }
+                Ok(document.body)
+            }
+            None => {
+                debug!("{} is unchanged since it was cached", url);
+                Ok(cached.unwrap().body)
+            }
+        }
+    }
+
+    /// Request the document, retrying failed connections, timeouts and server errors as
+    /// configured by the `fetch-retries`, `fetch-retry-mintimeout`, `fetch-retry-maxtimeout` and
+    /// `fetch-timeout` settings. Returns None if the registry answers that the document still has
+    /// the given ETag
+    fn request_document(
+        &self,
+        package: &str,
+        url: &str,
+        etag: Option<&str>,
+    ) -> Result<Option<CachedDocument>> {
+        let registry = self.get_registry(package);
+        let retries = self.get_setting("fetch-retries", 2);
+        let client = Client::builder()
+            .timeout(Duration::from_millis(
+                self.get_setting("fetch-timeout", 30_000),
+            ))
+            .build()?;
+
+        let mut attempt = 0;
+        loop {
+            let mut request = client.get(url).header(ACCEPT, ABBREVIATED_METADATA);
+            if let Some(token) = self.config.get_auth_token(&registry) {
+                request = request.bearer_auth(token);
+            }
+            if let Some(etag) = etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+
+            let error = match request.send() {
+                Ok(response) if is_retryable(response.status()) && attempt < retries => {
+                    format!("{}", response.status())
+                }
+                Ok(response) if etag.is_some() && response.status() == StatusCode::NOT_MODIFIED => {
+                    return Ok(None)
+                }
+                Ok(response) => {
+                    let etag = response
+                        .headers()
+                        .get(ETAG)
+                        .and_then(|etag| etag.to_str().ok())
+                        .map(String::from);
+                    let body = response
+                        .error_for_status()
+                        .and_then(|response| response.text())
+                        .with_context(|| format!("Unable to fetch {} from {}", package, url))?;
+                    return Ok(Some(CachedDocument { etag, body }));
+                }
+                Err(e) if attempt < retries => format!("{}", e),
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Unable to fetch {} from {}", package, url))
+                }
+            };
+
+            attempt += 1;
+            let backoff = self.get_backoff(attempt);
+            warn!(
+                "Fetching {} failed ({}), retrying in {}ms",
+                url, error, backoff
+            );
+            thread::sleep(Duration::from_millis(backoff));
+        }
+    }
+
+    /// Milliseconds to wait before the given retry, doubling with every retry up to the maximum
+    fn get_backoff(&self, attempt: u64) -> u64 {
+        // saturate instead of overflowing after many retries
+        let factor = 1u64
+            .checked_shl(cmp::min(attempt - 1, 64) as u32)
+            .unwrap_or(u64::MAX);
+        cmp::min(
+            self.get_setting("fetch-retry-mintimeout", 1_000)
+                .saturating_mul(factor),
+            self.get_setting("fetch-retry-maxtimeout", 60_000),
+        )
+    }
+
+    fn get_setting(&self, key: &str, default: u64) -> u64 {
+        self.config
+            .get(key)
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(default)
+    }
+}
 
 impl PackageManager for Npm {
-    fn get_latest_version_value(package: &str) -> Result<String> {
-        // TODO Find a better api url
-        let body =
-            reqwest::blocking::get(format!("https://registry.npmjs.org/{}", package).as_str())?
-                .text()?;
+    fn get_version_value(&self, package: &str, query: &VersionQuery) -> Result<String> {
+        let body = self.fetch_document(package)?;
 
         let registry_object: NpmRegistryObject = serde_json::from_reader(body.as_bytes())?;
 
-        let latest = registry_object.tags.latest
-            .map(|v| Version::parse(v.as_str()))
-            .unwrap_or(Ok(Version::new(0, 0, 0)))
-            .unwrap_or(Version::new(0, 0, 0));
+        let version = match query {
+            VersionQuery::Latest => {
+                let get_tag = |tag: &str| {
+                    registry_object
+                        .tags
+                        .get(tag)
+                        .map(|v| Version::parse(v.as_str()))
+                        .unwrap_or(Ok(Version::new(0, 0, 0)))
+                        .unwrap_or(Version::new(0, 0, 0))
+                };
+
+                let newest = cmp::max(get_tag("latest"), get_tag("rc"));
+                info!("Found latest version: {} for {}", newest, package);
+                newest.to_string()
+            }
+            VersionQuery::Tag(tag) => {
+                let version = registry_object
+                    .tags
+                    .get(tag)
+                    .ok_or_else(|| anyhow!("{} has no {} dist-tag", package, tag))?;
+                info!("Found version {} tagged {} for {}", version, tag, package);
+                version.clone()
+            }
+            VersionQuery::Range(range) => {
+                let newest = registry_object
+                    .versions
+                    .keys()
+                    .filter_map(|v| Version::parse(v).ok())
+                    .filter(|v| range.matches(v))
+                    .max()
+                    .ok_or_else(|| {
+                        anyhow!("No published version of {} matches {}", package, range)
+                    })?;
+                info!(
+                    "Found version {} matching {} for {}",
+                    newest, range, package
+                );
+                newest.to_string()
+            }
+        };
+
+        Ok(version)
+    }
+}
+
+/// The package name as it appears in registry URLs, with the slash of scoped names encoded
+fn encode_name(package: &str) -> String {
+    package.replace('/', "%2f")
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
 
-        let rc = registry_object.tags.rc
-            .map(|v| Version::parse(v.as_str()))
-            .unwrap_or(Ok(Version::new(0, 0, 0)))
-            .unwrap_or(Version::new(0, 0, 0));
+    /// An empty directory of the test's own for the metadata cache
+    fn state_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("lpm-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// A stand-in registry answering each connection with the next of the responses, given as
+    /// status line, body and delay before answering. Returns its URL and the requests it received
+    fn serve(
+        responses: Vec<(&'static str, &'static str, u64)>,
+    ) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+
+        thread::spawn(move || {
+            for (status, body, delay) in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = String::new();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                        break;
+                    }
+                    request.push_str(&line);
+                }
+                received.lock().unwrap().push(request);
+
+                thread::sleep(Duration::from_millis(delay));
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nETag: \"v1\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+            }
+        });
+        (url, requests)
+    }
+
+    fn npm(npmrc: &str) -> Npm {
+        Npm::with_config(NpmConfig::parse(npmrc), None)
+    }
+
+    const DOCUMENT: &str = r#"{"dist-tags":{"latest":"4.2.0"}}"#;
+
+    #[test]
+    fn encodes_scoped_names() {
+        let npm = npm("registry=https://registry.example.com");
+        assert_eq!(
+            npm.get_package_url("@bitgo/statics"),
+            "https://registry.example.com/@bitgo%2fstatics"
+        );
+        assert_eq!(
+            npm.get_package_url("lodash"),
+            "https://registry.example.com/lodash"
+        );
+    }
+
+    #[test]
+    fn requests_abbreviated_metadata_with_auth() {
+        let (url, requests) = serve(vec![("200 OK", DOCUMENT, 0)]);
+        let npm = npm(&format!(
+            "@bitgo:registry={}private/\n{}private/:_authToken=secret",
+            url,
+            url.trim_start_matches("http:")
+        ));
+
+        assert_eq!(
+            npm.get_version_value("@bitgo/statics", &VersionQuery::Latest)
+                .unwrap(),
+            "4.2.0"
+        );
+        let request = requests.lock().unwrap()[0].to_lowercase();
+        assert!(request.starts_with("get /private/@bitgo%2fstatics "));
+        assert!(request.contains(&format!("accept: {}", ABBREVIATED_METADATA.to_lowercase())));
+        assert!(request.contains("authorization: bearer secret"));
+    }
+
+    #[test]
+    fn caps_backoff_of_many_retries() {
+        let npm = npm("fetch-retry-mintimeout=1000\nfetch-retry-maxtimeout=5000");
+        assert_eq!(npm.get_backoff(1), 1_000);
+        assert_eq!(npm.get_backoff(3), 4_000);
+        assert_eq!(npm.get_backoff(4), 5_000);
+        assert_eq!(npm.get_backoff(60), 5_000);
+        assert_eq!(npm.get_backoff(200), 5_000);
+    }
+
+    #[test]
+    fn retries_server_errors() {
+        let (url, requests) = serve(vec![
+            ("503 Service Unavailable", "", 0),
+            ("429 Too Many Requests", "", 0),
+            ("200 OK", DOCUMENT, 0),
+        ]);
+        let npm = npm(&format!(
+            "registry={}\nfetch-retries=2\nfetch-retry-mintimeout=1",
+            url
+        ));
+
+        assert_eq!(
+            npm.get_version_value("lodash", &VersionQuery::Latest)
+                .unwrap(),
+            "4.2.0"
+        );
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn gives_up_after_retries() {
+        let (url, requests) = serve(vec![
+            ("500 Internal Server Error", "", 0),
+            ("500 Internal Server Error", "", 0),
+        ]);
+        let npm = npm(&format!(
+            "registry={}\nfetch-retries=1\nfetch-retry-mintimeout=1",
+            url
+        ));
+
+        assert!(npm
+            .get_version_value("lodash", &VersionQuery::Latest)
+            .is_err());
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn does_not_retry_missing_packages() {
+        let (url, requests) = serve(vec![("404 Not Found", "{}", 0), ("200 OK", DOCUMENT, 0)]);
+        let npm = npm(&format!(
+            "registry={}\nfetch-retries=2\nfetch-retry-mintimeout=1",
+            url
+        ));
+
+        assert!(npm
+            .get_version_value("missing", &VersionQuery::Latest)
+            .is_err());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn times_out() {
+        let (url, _) = serve(vec![("200 OK", DOCUMENT, 2_000)]);
+        let npm = npm(&format!(
+            "registry={}\nfetch-retries=0\nfetch-timeout=100",
+            url
+        ));
+
+        assert!(npm
+            .get_version_value("lodash", &VersionQuery::Latest)
+            .is_err());
+    }
+
+    const CHANNELS: &str = r#"{
+        "dist-tags": {"latest": "4.9.0", "beta": "5.0.0-beta.1"},
+        "versions": {"3.1.0": {}, "4.2.0": {}, "4.9.0": {}, "4.10.0-rc.1": {}, "5.0.0-beta.1": {}}
+    }"#;
+
+    #[test]
+    fn resolves_dist_tags() {
+        let (url, _) = serve(vec![("200 OK", CHANNELS, 0), ("200 OK", CHANNELS, 0)]);
+        let npm = npm(&format!("registry={}", url));
+
+        let beta = VersionQuery::Tag("beta".to_string());
+        assert_eq!(
+            npm.get_version_value("lodash", &beta).unwrap(),
+            "5.0.0-beta.1"
+        );
+        let next = VersionQuery::Tag("next".to_string());
+        assert!(npm.get_version_value("lodash", &next).is_err());
+    }
+
+    #[test]
+    fn resolves_newest_version_in_range() {
+        let (url, _) = serve(vec![("200 OK", CHANNELS, 0), ("200 OK", CHANNELS, 0)]);
+        let npm = npm(&format!("registry={}", url));
+
+        let range = VersionQuery::Range(VersionReq::parse("4.x").unwrap());
+        assert_eq!(npm.get_version_value("lodash", &range).unwrap(), "4.9.0");
+        let range = VersionQuery::Range(VersionReq::parse("^6").unwrap());
+        assert!(npm.get_version_value("lodash", &range).is_err());
+    }
+
+    #[test]
+    fn revalidates_cached_documents() {
+        let (url, requests) = serve(vec![("200 OK", DOCUMENT, 0), ("304 Not Modified", "", 0)]);
+        let npmrc = NpmConfig::parse(&format!("registry={}", url));
+        let npm = Npm::with_config(
+            npmrc,
+            Some(MetadataCache::new(&state_dir("revalidate"), false)),
+        );
+
+        for _ in 0..2 {
+            assert_eq!(
+                npm.get_version_value("lodash", &VersionQuery::Latest)
+                    .unwrap(),
+                "4.2.0"
+            );
+        }
+        let requests = requests.lock().unwrap();
+        assert!(!requests[0].to_lowercase().contains("if-none-match"));
+        assert!(requests[1].to_lowercase().contains("if-none-match: \"v1\""));
+    }
 
-        let newest = cmp::max(latest, rc);
-        info!("Found latest version: {} for {}", newest, package);
+    #[test]
+    fn resolves_only_from_cache_when_offline() {
+        let (url, requests) = serve(vec![("200 OK", DOCUMENT, 0)]);
+        let npmrc = format!("registry={}", url);
+        let dir = state_dir("offline");
+        let online = Npm::with_config(
+            NpmConfig::parse(&npmrc),
+            Some(MetadataCache::new(&dir, false)),
+        );
+        online
+            .get_version_value("lodash", &VersionQuery::Latest)
+            .unwrap();
 
-        Ok(newest.to_string())
+        let offline = Npm::with_config(
+            NpmConfig::parse(&npmrc),
+            Some(MetadataCache::new(&dir, true)),
+        );
+        assert_eq!(
+            offline
+                .get_version_value("lodash", &VersionQuery::Latest)
+                .unwrap(),
+            "4.2.0"
+        );
+        assert!(offline
+            .get_version_value("react", &VersionQuery::Latest)
+            .is_err());
+        assert_eq!(requests.lock().unwrap().len(), 1);
     }
 }
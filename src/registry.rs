@@ -1,147 +1,1131 @@
-use crate::package::{Bundle, Package, Typescript};
-use anyhow::Result;
-use log::info;
+use crate::cache::{self, Cache};
+use crate::graph::Graph;
+use crate::link::{self, LinkMode};
+use crate::metadata::MetadataCache;
+use crate::package::{Bundle, DependencyType, Package, Typescript};
+use crate::package_manager::{Npm, PackageManager, VersionQuery};
+use crate::runner::Runner;
+use crate::state::State;
+use anyhow::{anyhow, bail, Context, Result};
+use blake2::{Blake2b, Digest};
+use log::{debug, error, info, warn};
 use solvent::DepGraph;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+/// The version a dependency is reset to
+pub enum ResetVersion {
+    /// The spec the dependency had before lpm first pointed it at the local package
+    Original,
+    /// The published version matching the query
+    Published(VersionQuery),
+    /// The given spec
+    Exact(String),
+}
 
 pub struct Registry {
     packages: Packages,
     graph: DepGraph<PathBuf>,
+    /// The registered packages each package directly depends on, with the package.json sections
+    /// listing each
+    edges: HashMap<PathBuf, HashMap<PathBuf, Vec<DependencyType>>>,
+    runner: Arc<dyn Runner>,
+    /// The package.json sections update, reset and bundle rewrite
+    dependency_types: Vec<DependencyType>,
+    /// How update puts local dependencies into node_modules, instead of rewriting package.json
+    link_mode: Option<LinkMode>,
+    metadata_cache: Option<MetadataCache>,
 }
 
 impl Registry {
-    pub fn new() -> Registry {
+    pub fn new(runner: Arc<dyn Runner>) -> Registry {
         Registry {
             packages: Packages::new(),
             graph: DepGraph::new(),
+            edges: HashMap::new(),
+            runner,
+            dependency_types: DependencyType::ALL.to_vec(),
+            link_mode: None,
+            metadata_cache: None,
         }
     }
 
+    /// Link local dependencies into node_modules when updating, leaving package.json alone
+    pub fn set_link_mode(&mut self, link_mode: LinkMode) {
+        self.link_mode = Some(link_mode);
+    }
+
+    /// Keep the registry documents fetched when resetting in the cache, and revalidate them
+    pub fn set_metadata_cache(&mut self, metadata_cache: MetadataCache) {
+        self.metadata_cache = Some(metadata_cache);
+    }
+
+    /// Only rewrite dependencies listed in the given package.json sections
+    pub fn set_dependency_types(&mut self, dependency_types: Vec<DependencyType>) {
+        self.dependency_types = dependency_types;
+    }
+
     pub fn add(&mut self, package: impl Package + 'static) {
-        let graph = &mut self.graph;
+        self.graph.register_node(package.get_path());
+        self.edges.entry(package.get_path()).or_default();
 
-        self.packages.values().for_each(|other| {
-            if package.depends_on(&other.get_name()) {
-                info!(
-                    "Package {:?} depends on {:?}",
-                    package.get_name(),
-                    other.get_name()
+        let others: Vec<(PathBuf, String)> = self
+            .packages
+            .values()
+            .map(|other| (other.get_path(), other.get_name()))
+            .collect();
+        for (other_path, other_name) in others {
+            let dependency_types = Registry::get_dependency_types(&package, &other_name);
+            if !dependency_types.is_empty() {
+                self.register_dependency(
+                    &package.get_name(),
+                    package.get_path(),
+                    &other_name,
+                    other_path.clone(),
+                    dependency_types,
                 );
-                graph.register_dependency(package.get_path().clone(), other.get_path().clone());
-            } else if other.depends_on(&package.get_name()) {
-                info!(
-                    "Package {:?} depends on {:?}",
-                    other.get_name(),
-                    package.get_name()
+            }
+            let dependency_types =
+                Registry::get_dependency_types(&*self.packages[&other_path], &package.get_name());
+            if !dependency_types.is_empty() {
+                self.register_dependency(
+                    &other_name,
+                    other_path,
+                    &package.get_name(),
+                    package.get_path(),
+                    dependency_types,
                 );
-                graph.register_dependency(other.get_path().clone(), package.get_path().clone());
             }
-        });
+        }
 
         self.packages
             .insert(package.get_path().clone(), Box::new(package));
     }
 
-    pub fn update_dependencies(&mut self, path: PathBuf) {
-        self.for_each_dependency(
-            path.clone(),
-            move |dependent: &mut Box<dyn Package>,
-                  mut dependency: Box<dyn Package>,
-                  processed_packages: &Vec<PathBuf>| {
-                processed_packages.iter().for_each(|processed_package| {
-                    // TODO Fix this hacky reinstantiation
-                    dependency.update(Box::new(Typescript::new(processed_package.to_path_buf())));
-                });
+    /// The package.json sections lpm treats the package as depending on the named package in,
+    /// none if the dependency is ignored
+    fn get_dependency_types(package: &dyn Package, dependency_name: &str) -> Vec<DependencyType> {
+        let dependency_types = package.get_dependency_types(dependency_name);
+        let ignored = package
+            .get_options()
+            .ignored_dependencies
+            .iter()
+            .any(|ignored| ignored == dependency_name);
+        if ignored && !dependency_types.is_empty() {
+            debug!(
+                "Ignoring dependency of {:?} on {:?}",
+                package.get_name(),
+                dependency_name
+            );
+            return Vec::new();
+        }
+        dependency_types
+    }
+
+    fn register_dependency(
+        &mut self,
+        name: &str,
+        path: PathBuf,
+        dependency_name: &str,
+        dependency_path: PathBuf,
+        dependency_types: Vec<DependencyType>,
+    ) {
+        info!(
+            "Package {:?} depends on {:?} in {}",
+            name,
+            dependency_name,
+            dependency_types
+                .iter()
+                .map(DependencyType::get_key)
+                .collect::<Vec<&str>>()
+                .join(", ")
+        );
+        self.graph
+            .register_dependency(path.clone(), dependency_path.clone());
+        self.edges
+            .entry(path)
+            .or_default()
+            .insert(dependency_path, dependency_types);
+    }
 
-                dependency.prepare();
+    /// Every dependency cycle between registered packages, each starting and ending with the same
+    /// package
+    pub fn find_cycles(&self) -> Vec<Vec<PathBuf>> {
+        let mut paths: Vec<&PathBuf> = self.edges.keys().collect();
+        paths.sort();
 
-                dependent.update(dependency);
+        let mut cycles = Vec::new();
+        let mut visited: HashSet<PathBuf> = HashSet::new();
+        for path in paths {
+            self.find_cycles_from(path, &mut Vec::new(), &mut visited, &mut cycles);
+        }
+        cycles
+    }
+
+    fn find_cycles_from(
+        &self,
+        path: &Path,
+        stack: &mut Vec<PathBuf>,
+        visited: &mut HashSet<PathBuf>,
+        cycles: &mut Vec<Vec<PathBuf>>,
+    ) {
+        if let Some(start) = stack.iter().position(|on_stack| on_stack == path) {
+            let mut cycle = stack[start..].to_vec();
+            cycle.push(path.to_path_buf());
+            cycles.push(cycle);
+            return;
+        }
+        if !visited.insert(path.to_path_buf()) {
+            return;
+        }
+
+        let mut dependencies: Vec<&PathBuf> = self.edges[path].keys().collect();
+        dependencies.sort();
+        stack.push(path.to_path_buf());
+        for dependency in dependencies {
+            self.find_cycles_from(dependency, stack, visited, cycles);
+        }
+        stack.pop();
+    }
+
+    /// Describe the cycle by package name and path, with a hint on how to break it
+    pub fn describe_cycle(&self, cycle: &[PathBuf]) -> String {
+        let packages: Vec<String> = cycle
+            .iter()
+            .map(|path| format!("{} ({:?})", self.packages[path].get_name(), path))
+            .collect();
+        format!(
+            "Dependency cycle: {}\nBreak it by ignoring one of these dependencies, e.g. `lpm config {} --ignore-dependency {}`",
+            packages.join(" -> "),
+            self.packages[&cycle[0]].get_name(),
+            self.packages[&cycle[1]].get_name()
+        )
+    }
+
+    /// Find the registered package with the given path, package name, or unambiguous package name
+    /// prefix. Scoped names may be matched with or without their scope
+    pub fn resolve(&self, key: &str) -> Result<PathBuf> {
+        let path = fs::canonicalize(key).ok();
+        if let Some(path) = &path {
+            if self.packages.contains_key(path) {
+                return Ok(path.clone());
+            }
+        }
+
+        let exact = self.find_by_name(key);
+        if exact.len() > 1 {
+            bail!(
+                "Multiple registered packages are named {}, refer to one by path: {:?}",
+                key,
+                exact
+            );
+        } else if let Some(found) = exact.into_iter().next() {
+            return Ok(found);
+        }
+
+        // scoped names may be given without their scope
+        let mut unscoped: Vec<(String, PathBuf)> = self
+            .packages
+            .values()
+            .map(|package| (package.get_name(), package.get_path()))
+            .filter(|(name, _)| name.rsplit('/').next() == Some(key))
+            .collect();
+        unscoped.sort();
+        match unscoped.len() {
+            0 => {}
+            1 => return Ok(unscoped.remove(0).1),
+            _ => bail!(
+                "{} names multiple registered packages, refer to one by its full name: {}",
+                key,
+                describe_matches(&unscoped)
+            ),
+        }
+
+        let mut prefixed: Vec<(String, PathBuf)> = self
+            .packages
+            .values()
+            .map(|package| (package.get_name(), package.get_path()))
+            .filter(|(name, _)| {
+                let unscoped = name.rsplit('/').next().unwrap_or(name);
+                name.starts_with(key) || unscoped.starts_with(key)
+            })
+            .collect();
+        prefixed.sort();
+        match prefixed.len() {
+            1 => Ok(prefixed.remove(0).1),
+            0 => match path {
+                Some(path) => Err(anyhow!("Package {:?} is not registered", path)),
+                None => Err(anyhow!("No registered package matches {}", key)),
             },
-        );
+            _ => Err(anyhow!(
+                "{} matches multiple registered packages: {}",
+                key,
+                describe_matches(&prefixed)
+            )),
+        }
+    }
+
+    /// Paths of the registered packages with the given package name
+    pub fn find_by_name(&self, name: &str) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self
+            .packages
+            .values()
+            .filter(|package| package.get_name() == name)
+            .map(|package| package.get_path())
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    /// Link the package to the registered packages it depends on, directly or indirectly, and
+    /// prepare them all
+    pub fn update_dependencies(
+        &mut self,
+        path: PathBuf,
+        state: &mut State,
+        force: bool,
+        jobs: usize,
+    ) -> Result<()> {
+        let order = self.dependency_order(&path)?;
+        self.update_packages(&order, state, force, jobs)
+    }
+
+    /// Link every registered package to the registered packages it depends on and prepare them
+    /// all, each once
+    pub fn update_all(&mut self, state: &mut State, force: bool, jobs: usize) -> Result<()> {
+        let order = self.topological_order()?;
+        self.update_packages(&order, state, force, jobs)
+    }
+
+    /// Link every registered package depending on the package, directly or indirectly, and
+    /// prepare them along with the package itself
+    pub fn update_dependents(
+        &mut self,
+        path: PathBuf,
+        state: &mut State,
+        force: bool,
+        jobs: usize,
+    ) -> Result<()> {
+        let mut dependents = self.dependents_of(&path);
+        dependents.push(path);
+        let order = self.order_of(&dependents)?;
+        self.update_packages(&order, state, force, jobs)
+    }
 
-        self.packages.get(&path).expect("Unknown path").prepare();
+    /// Prepare the changed packages among those the package depends on, then link and prepare the
+    /// packages depending on them, up to and including the package itself
+    pub fn update_changed(
+        &mut self,
+        path: &Path,
+        changed: &[PathBuf],
+        state: &mut State,
+        jobs: usize,
+    ) -> Result<()> {
+        let mut affected: Vec<PathBuf> = Vec::new();
+        for package_path in self.dependency_order(path)? {
+            let dependencies = self.dependency_order(&package_path)?;
+            if dependencies
+                .iter()
+                .any(|dependency| changed.contains(dependency))
+            {
+                affected.push(package_path);
+            }
+        }
+        self.update_packages(&affected, state, false, jobs)
     }
 
-    pub fn bundle_dependencies(&mut self, path: PathBuf) {
+    /// Point each of the packages, given in topological order, at the registered packages it
+    /// directly depends on, then prepare them
+    fn update_packages(
+        &mut self,
+        order: &[PathBuf],
+        state: &mut State,
+        force: bool,
+        jobs: usize,
+    ) -> Result<()> {
+        self.plan_order(order);
+        let mut changed: HashSet<PathBuf> = HashSet::new();
+
+        // linked dependencies are put into node_modules as each dependent is prepared instead
+        let order_to_rewrite = if self.link_mode.is_some() { &[] } else { order };
+        for path in order_to_rewrite {
+            let mut dependent = self.instantiate(path);
+            let mut dependencies: Vec<&PathBuf> = self.edges[path].keys().collect();
+            dependencies.sort();
+
+            for dependency in dependencies {
+                let dependency = Box::new(self.instantiate(dependency));
+                if Registry::update_dependency(
+                    state,
+                    &*self.runner,
+                    &self.dependency_types,
+                    &mut dependent,
+                    dependency,
+                )? {
+                    changed.insert(path.clone());
+                }
+            }
+        }
+
+        let packages: Vec<Box<dyn Package>> = order
+            .iter()
+            .map(|package_path| Box::new(self.instantiate(package_path)) as Box<dyn Package>)
+            .collect();
+        self.prepare_all(&packages, &mut state.cache, &changed, force, jobs)
+    }
+
+    /// Point the dependent at the dependency in the given sections, remembering the specs it
+    /// replaced. Returns whether any spec changed
+    fn update_dependency(
+        state: &mut State,
+        runner: &dyn Runner,
+        dependency_types: &[DependencyType],
+        dependent: &mut dyn Package,
+        dependency: Box<dyn Package>,
+    ) -> Result<bool> {
+        let dependency_name = dependency.get_name();
+        let version =
+            dependency.get_version_value(&dependent.get_path(), dependent.get_options().file_spec);
+        let mut changed = false;
+
+        for (dependency_type, previous) in dependent.update(dependency, dependency_types, runner)? {
+            if !runner.is_dry_run() {
+                state.original_specs.record(
+                    dependency_type,
+                    &dependent.get_path(),
+                    &dependency_name,
+                    &previous,
+                );
+            }
+            changed |= previous != version;
+        }
+        Ok(changed)
+    }
+
+    /// Prepare the packages, given in topological order, running up to `jobs` preparations at
+    /// once. Each package is started once its dependencies among the packages are finished, and
+    /// is only prepared if it changed since its last preparation, its dependency specs changed,
+    /// or one of those dependencies was prepared. After a failure no more packages are started,
+    /// and the first failure is returned once the running preparations finish
+    fn prepare_all(
+        &self,
+        packages: &[Box<dyn Package>],
+        cache: &mut Cache,
+        changed: &HashSet<PathBuf>,
+        force: bool,
+        jobs: usize,
+    ) -> Result<()> {
+        let runner = &*self.runner;
+        // keep a dry run's plan in order
+        let jobs = if runner.is_dry_run() { 1 } else { jobs.max(1) };
+        let paths: HashSet<PathBuf> = packages.iter().map(|package| package.get_path()).collect();
+        let dependencies: HashMap<PathBuf, Vec<PathBuf>> = packages
+            .iter()
+            .map(|package| {
+                let path = package.get_path();
+                let dependencies = self.edges[&path]
+                    .keys()
+                    .filter(|dependency| paths.contains(*dependency))
+                    .cloned()
+                    .collect();
+                (path, dependencies)
+            })
+            .collect();
+
+        let linked: HashMap<PathBuf, Vec<&dyn Package>> = packages
+            .iter()
+            .map(|package| {
+                let path = package.get_path();
+                let linked = packages
+                    .iter()
+                    .filter(|dependency| {
+                        self.edges[&path].get(&dependency.get_path()).is_some_and(
+                            |dependency_types| {
+                                dependency_types.iter().any(|dependency_type| {
+                                    self.dependency_types.contains(dependency_type)
+                                })
+                            },
+                        )
+                    })
+                    .map(|dependency| &**dependency)
+                    .collect();
+                (path, linked)
+            })
+            .collect();
+        let link_mode = self.link_mode;
+
+        let mut pending: Vec<&dyn Package> = packages.iter().map(|package| &**package).collect();
+        let mut running: HashSet<PathBuf> = HashSet::new();
+        let mut finished: HashSet<PathBuf> = HashSet::new();
+        let mut keys: HashMap<PathBuf, String> = HashMap::new();
+        let mut failure: Option<anyhow::Error> = None;
+
+        thread::scope(|scope| {
+            let (sender, receiver) = mpsc::channel();
+
+            loop {
+                while failure.is_none() && running.len() < jobs {
+                    let ready = pending.iter().position(|package| {
+                        dependencies[&package.get_path()]
+                            .iter()
+                            .all(|dependency| finished.contains(dependency))
+                    });
+                    let package = match ready {
+                        Some(index) => pending.remove(index),
+                        None => break,
+                    };
+
+                    let path = package.get_path();
+                    let force = force || changed.contains(&path);
+                    if !self.start_preparation(package, cache, &mut keys, force) {
+                        if let Some(mode) = link_mode {
+                            // the links may be missing even though the package is up to date
+                            let links = linked[&path].iter().try_for_each(|dependency| {
+                                link::link(package, *dependency, mode, runner)
+                            });
+                            if let Err(e) = links {
+                                failure = Some(e);
+                                break;
+                            }
+                        }
+                        finished.insert(path);
+                        continue;
+                    }
+
+                    running.insert(path.clone());
+                    let sender = sender.clone();
+                    let linked = &linked[&path];
+                    scope.spawn(move || {
+                        let result = match link_mode {
+                            Some(mode) => link::prepare_linked(package, linked, mode, runner),
+                            None => package.prepare(runner),
+                        };
+                        sender.send((path, result))
+                    });
+                }
+
+                if running.is_empty() {
+                    break;
+                }
+
+                let (path, result) = receiver.recv().expect("a running preparation to finish");
+                running.remove(&path);
+                match result {
+                    Ok(()) => {
+                        self.finish_preparation(&path, cache, &mut keys);
+                        finished.insert(path);
+                    }
+                    Err(e) if failure.is_none() => failure = Some(e),
+                    Err(e) => error!("{:?}", e),
+                }
+            }
+        });
+
+        failure.map_or(Ok(()), Err)
+    }
+
+    /// Prepare the package unless its cache key matches its last successful preparation. Returns
+    /// whether the package was prepared
+    fn prepare_cached(&self, path: &Path, cache: &mut Cache, force: bool) -> Result<bool> {
+        let package = self.get_package(path)?;
+        let mut keys = HashMap::new();
+        if !self.start_preparation(package, cache, &mut keys, force) {
+            return Ok(false);
+        }
+        package.prepare(&*self.runner)?;
+        self.finish_preparation(path, cache, &mut keys);
+        Ok(true)
+    }
+
+    /// Whether the package needs preparing, as it is forced or its cache key differs from its last
+    /// successful preparation. If so, that preparation is forgotten, so a failure is never
+    /// mistaken for an up-to-date build
+    fn start_preparation(
+        &self,
+        package: &dyn Package,
+        cache: &mut Cache,
+        keys: &mut HashMap<PathBuf, String>,
+        force: bool,
+    ) -> bool {
+        let path = package.get_path();
+        let stale = force
+            || self
+                .get_cache_key(&path, keys)
+                .is_none_or(|key| cache.has_changed(&path, &key));
+        if !stale {
+            info!("Skipping unchanged package: {}", package.get_name());
+            return false;
+        }
+
+        if !self.runner.is_dry_run() {
+            cache.remove(&path);
+        }
+        true
+    }
+
+    /// Record the successful preparation, so the package is skipped until its key changes. The
+    /// key is computed again, as installing rewrites hashed inputs such as the lockfile
+    fn finish_preparation(
+        &self,
+        path: &Path,
+        cache: &mut Cache,
+        keys: &mut HashMap<PathBuf, String>,
+    ) {
+        if self.runner.is_dry_run() {
+            return;
+        }
+        keys.remove(path);
+        if let Some(key) = self.get_cache_key(path, keys) {
+            cache.update(path, key);
+        }
+    }
+
+    /// The cache key of the package, which changes with its sources and with the keys of the
+    /// registered packages it depends on, so a package is stale whenever anything it is built
+    /// against changed. Keys already computed are kept in the given map. Errors are logged, as
+    /// a package whose key is unknown is always stale
+    fn get_cache_key(&self, path: &Path, keys: &mut HashMap<PathBuf, String>) -> Option<String> {
+        if let Some(key) = keys.get(path) {
+            return Some(key.clone());
+        }
+
+        let mut hasher = Blake2b::new();
+        let hash = match cache::get_package_hash(path, self.packages[path].get_options()) {
+            Ok(hash) => hash,
+            Err(e) => {
+                error!("Error getting package hash: {:?}", e);
+                return None;
+            }
+        };
+        hasher.input(hash);
+        // a cycle leads back to a package whose key is in progress, which is left out
+        keys.insert(path.to_path_buf(), String::new());
+        let mut dependencies: Vec<&PathBuf> = self.edges[path].keys().collect();
+        dependencies.sort();
+        for dependency in dependencies {
+            let key = self.get_cache_key(dependency, keys);
+            hasher.input(dependency.to_string_lossy().as_bytes());
+            hasher.input(key.unwrap_or_default());
+        }
+
+        let key = hex::encode(hasher.result());
+        keys.insert(path.to_path_buf(), key.clone());
+        Some(key)
+    }
+
+    /// Whether the package, or a registered package it depends on, changed since the package was
+    /// last prepared
+    pub fn has_changed(&self, path: &Path, cache: &Cache) -> bool {
+        self.get_cache_key(path, &mut HashMap::new())
+            .is_none_or(|key| cache.has_changed(path, &key))
+    }
+
+    /// Prepare the registered packages which changed since they were last prepared, dependencies
+    /// first. Returns every registered package in that order
+    pub fn prepare_changed(&self, state: &mut State) -> Result<Vec<PathBuf>> {
+        let order = self.topological_order()?;
+        for path in &order {
+            self.prepare_cached(path, &mut state.cache, false)?;
+        }
+        Ok(order)
+    }
+
+    pub fn bundle_dependencies(&mut self, path: PathBuf, state: &mut State) -> Result<()> {
+        let runner = self.runner.clone();
+        let dependency_types = self.dependency_types.clone();
+        self.plan_order(&self.dependency_order(&path)?);
+
         self.for_each_dependency(
             path.clone(),
-            move |dependent: &mut Box<dyn Package>,
-                  dependency: Box<dyn Package>,
-                  processed_packages: &Vec<PathBuf>| {
+            |dependent: &mut Box<dyn Package>,
+             dependency: Box<dyn Package>,
+             processed_packages: &Vec<PathBuf>| {
                 let mut dependency_bundle = Bundle::new(dependency);
 
-                processed_packages.iter().for_each(|processed_package| {
+                for processed_package in processed_packages {
                     // TODO Fix this hacky reinstantiation
-                    dependency_bundle.update(Box::new(Bundle::new(Box::new(Typescript::new(
-                        processed_package.to_path_buf(),
-                    )))));
-                });
+                    let processed =
+                        Bundle::new(Box::new(Typescript::new(processed_package.to_path_buf())));
+                    Registry::update_dependency(
+                        state,
+                        &*runner,
+                        &dependency_types,
+                        &mut dependency_bundle,
+                        Box::new(processed),
+                    )?;
+                }
 
-                dependency_bundle.prepare();
+                dependency_bundle.prepare(&*runner)?;
                 Registry::copy_tarball(
+                    &*runner,
                     &dependency_bundle,
                     Box::new(Typescript::new(dependent.get_path())),
-                );
+                )?;
 
-                dependent.update(Box::new(dependency_bundle));
+                Registry::update_dependency(
+                    state,
+                    &*runner,
+                    &dependency_types,
+                    &mut **dependent,
+                    Box::new(dependency_bundle),
+                )?;
+                Ok(())
             },
-        );
+        )?;
 
-        self.packages.get(&path).expect("Unknown path").prepare();
+        self.get_package(&path)?.prepare(&*self.runner)
+    }
+
+    /// Publish the package and the registered packages it depends on, or every registered package
+    /// if none is given, dependencies first. Before each package is published, its `file:` specs
+    /// pointing at local packages are replaced by the versions just published. Stops at the first
+    /// failure. Packages are recorded as they are published, and skipped by the next run unless
+    /// their version changed or it restarts, until a run finishes
+    pub fn publish(
+        &mut self,
+        path: Option<PathBuf>,
+        restart: bool,
+        state: &mut State,
+    ) -> Result<()> {
+        let runner = self.runner.clone();
+        if restart && !runner.is_dry_run() {
+            state.published.clear();
+        }
+        let order = match &path {
+            Some(path) => self.dependency_order(path)?,
+            None => self.topological_order()?,
+        };
+        self.plan_order(&order);
+        // published versions by package name
+        let mut versions: HashMap<String, String> = HashMap::new();
+
+        for path in order {
+            let dependency_names: Vec<String> = self.edges[&path]
+                .keys()
+                .map(|dependency| self.packages[dependency].get_name())
+                .collect();
+            let package = self.packages.get_mut(&path).unwrap();
+            let name = package.get_name();
+            let version = package.get_version()?;
+
+            if !restart && state.published.get(&path) == Some(&version) {
+                info!(
+                    "Skipping {}, {} was published by the previous run",
+                    name, version
+                );
+                versions.insert(name, version);
+                continue;
+            }
+
+            for dependency_name in dependency_names {
+                for dependency_type in package.get_dependency_types(&dependency_name) {
+                    if !self.dependency_types.contains(&dependency_type) {
+                        continue;
+                    }
+                    let spec = package
+                        .get_dependency_spec(dependency_type, &dependency_name)
+                        .unwrap();
+                    if !spec.starts_with("file:") {
+                        continue;
+                    }
+
+                    let published = versions.get(&dependency_name).ok_or_else(|| {
+                        anyhow!("{} was not published ahead of {}", dependency_name, name)
+                    })?;
+                    package.reset(
+                        dependency_type,
+                        dependency_name.clone(),
+                        published.clone(),
+                        &*runner,
+                    )?;
+                    if !runner.is_dry_run() {
+                        state
+                            .original_specs
+                            .remove(dependency_type, &path, &dependency_name);
+                    }
+                }
+            }
+
+            package.build(&*runner)?;
+            package.publish(&*runner)?;
+            if !runner.is_dry_run() {
+                // write through so a later failure never loses track of what was published
+                state.published.insert(path.clone(), version.clone());
+                state.store();
+            }
+            versions.insert(name, version);
+        }
+
+        if !runner.is_dry_run() {
+            state.published.clear();
+        }
+        Ok(())
+    }
+
+    pub fn test(&self, path: PathBuf) -> Result<()> {
+        self.get_package(&path)?.test(&*self.runner)
     }
 
     pub fn reset_dependency(
         &mut self,
         dependency_path: PathBuf,
-        version: Option<String>,
+        version: &ResetVersion,
+        state: &mut State,
     ) -> Result<()> {
-        let dependency = Typescript::new(dependency_path);
+        let runner = self.runner.clone();
+        let dependency_name = Typescript::new(dependency_path).get_name();
+        // published versions by registry
+        let mut published: HashMap<String, String> = HashMap::new();
+
+        let dependents: Vec<PathBuf> = self
+            .topological_order()?
+            .into_iter()
+            .filter(|path| self.packages[path].depends_on(&dependency_name))
+            .collect();
+        self.plan_order(&dependents);
+
         // update the given dependency in all packages
-        for (_, dependent) in self.packages.iter_mut() {
-            if dependent.depends_on(&dependency.get_name()) {
-                dependent.reset(dependency.get_name(), version.clone())?;
-                dependent.prepare();
+        for path in dependents {
+            let dependent = self.packages.get_mut(&path).unwrap();
+            let mut reset = false;
+
+            for dependency_type in dependent.get_dependency_types(&dependency_name) {
+                if !self.dependency_types.contains(&dependency_type) {
+                    continue;
+                }
+                let current = dependent
+                    .get_dependency_spec(dependency_type, &dependency_name)
+                    .unwrap();
+
+                let spec = match version {
+                    ResetVersion::Exact(spec) => spec.clone(),
+                    ResetVersion::Published(query) => {
+                        // each dependent's .npmrc decides which registry the dependency is from
+                        let npm = Npm::new(&path, self.metadata_cache.clone())?;
+                        let registry = npm.get_registry(&dependency_name);
+                        match published.get(&registry) {
+                            Some(spec) => spec.clone(),
+                            None => {
+                                let spec = npm.get_version_value(&dependency_name, query)?;
+                                published.insert(registry, spec.clone());
+                                spec
+                            }
+                        }
+                    }
+                    ResetVersion::Original => match state.original_specs.get(
+                        dependency_type,
+                        &path,
+                        &dependency_name,
+                    ) {
+                        Some(spec) => spec.clone(),
+                        None if !current.starts_with("file:") => {
+                            debug!(
+                                "{} already depends on {} {}",
+                                dependent.get_name(),
+                                dependency_name,
+                                current
+                            );
+                            continue;
+                        }
+                        None => bail!(
+                            "The original version of {} in the {} of {} is unknown, reset it with --latest, --tag, --range or --version",
+                            dependency_name,
+                            dependency_type.get_key(),
+                            dependent.get_name()
+                        ),
+                    },
+                };
+
+                dependent.reset(dependency_type, dependency_name.clone(), spec, &*runner)?;
+                if !runner.is_dry_run() {
+                    state
+                        .original_specs
+                        .remove(dependency_type, &path, &dependency_name);
+                }
+                reset = true;
+            }
+
+            if reset {
+                self.prepare_cached(&path, &mut state.cache, true)?;
             }
         }
         Ok(())
     }
 
+    /// Remove the package from the node_modules of every registered package it was linked into,
+    /// then prepare each of them again so their package manager reinstalls it
+    pub fn unlink_dependency(&mut self, dependency_path: PathBuf, state: &mut State) -> Result<()> {
+        let runner = self.runner.clone();
+        let dependency_name = self.get_package(&dependency_path)?.get_name();
+
+        let dependents: Vec<PathBuf> = self
+            .topological_order()?
+            .into_iter()
+            .filter(|path| self.edges[path].contains_key(&dependency_path))
+            .collect();
+        self.plan_order(&dependents);
+
+        for path in dependents {
+            let link_path = link::get_link_path(&path, &dependency_name);
+            if fs::symlink_metadata(&link_path).is_err() {
+                debug!("{:?} does not exist", link_path);
+                continue;
+            }
+
+            info!("Unlinking {} from {:?}", dependency_name, path);
+            runner.remove(&link_path)?;
+            self.prepare_cached(&path, &mut state.cache, true)?;
+        }
+        Ok(())
+    }
+
+    /// Copy the build output of the package which differs from the copy installed in the
+    /// node_modules of each registered package depending on it, optionally building it first.
+    /// Dependents whose installed copy depends on other packages than the package itself are
+    /// skipped, as they need a full update
+    pub fn sync(&self, path: PathBuf, build: bool) -> Result<()> {
+        let runner = &*self.runner;
+        let package = self.get_package(&path)?;
+        if build {
+            package.build(runner)?;
+        }
+
+        let outputs: Vec<(PathBuf, String)> = cache::get_output_files(&path)?
+            .into_iter()
+            .map(|file| {
+                let hash = cache::get_file_hash(&path.join(&file))?;
+                Ok((file, hash))
+            })
+            .collect::<Result<_>>()?;
+
+        for dependent in self.dependents_of(&path) {
+            let installed = link::get_link_path(&dependent, &package.get_name());
+            match fs::symlink_metadata(&installed) {
+                Err(_) => {
+                    debug!("{} is not installed in {:?}", package.get_name(), dependent);
+                    continue;
+                }
+                Ok(metadata) if metadata.file_type().is_symlink() => {
+                    debug!("{:?} is linked, nothing to sync", installed);
+                    continue;
+                }
+                Ok(_) => {}
+            }
+            if !Registry::has_same_dependencies(&path, &installed) {
+                warn!(
+                    "The dependencies of {} changed since it was installed in {:?}, run lpm update instead",
+                    package.get_name(),
+                    dependent
+                );
+                continue;
+            }
+
+            let mut copied = 0;
+            for (file, hash) in &outputs {
+                let target = installed.join(file);
+                if cache::get_file_hash(&target).ok().as_ref() != Some(hash) {
+                    runner.copy(&path.join(file), &target)?;
+                    copied += 1;
+                }
+            }
+            // outputs the package no longer builds
+            for file in cache::get_output_files(&installed)? {
+                if !outputs.iter().any(|(output, _)| *output == file) {
+                    runner.remove(&installed.join(file))?;
+                }
+            }
+            info!(
+                "Synced {} changed files of {} into {:?}",
+                copied,
+                package.get_name(),
+                dependent
+            );
+        }
+        Ok(())
+    }
+
+    /// Whether the package.json files of the packages list the same dependencies
+    fn has_same_dependencies(path: &Path, other: &Path) -> bool {
+        let read = |path: &Path| -> Option<serde_json::Value> {
+            let contents = fs::read(path.join("package.json")).ok()?;
+            serde_json::from_slice(&contents).ok()
+        };
+        match (read(path), read(other)) {
+            (Some(package_json), Some(other_package_json)) => DependencyType::ALL
+                .iter()
+                .filter(|dependency_type| **dependency_type != DependencyType::DevDependencies)
+                .all(|dependency_type| {
+                    package_json.get(dependency_type.get_key())
+                        == other_package_json.get(dependency_type.get_key())
+                }),
+            _ => false,
+        }
+    }
+
+    /// Show the order packages are processed in as part of a dry run's plan
+    fn plan_order(&self, paths: &[PathBuf]) {
+        if !self.runner.is_dry_run() {
+            return;
+        }
+
+        println!("Order:");
+        paths.iter().enumerate().for_each(|(index, path)| {
+            println!(
+                "\t {}. {} {:?}",
+                index + 1,
+                self.packages[path].get_name(),
+                path
+            )
+        });
+    }
+
     pub fn for_each_dependency(
         &mut self,
         path: PathBuf,
-        f: impl Fn(&mut Box<dyn Package>, Box<dyn Package>, &Vec<PathBuf>),
-    ) {
-        // TODO handle errors better in this fn
+        mut f: impl FnMut(&mut Box<dyn Package>, Box<dyn Package>, &Vec<PathBuf>) -> Result<()>,
+    ) -> Result<()> {
         // TODO Remove need to instantiate concrete types here so this func can work for different
         // package types
+        let dependencies: Vec<Typescript> = self
+            .dependency_order(&path)?
+            .iter()
+            .filter(|dependency_path| **dependency_path != path)
+            .map(|dependency_path| self.instantiate(dependency_path))
+            .collect();
         let package = self.packages.get_mut(&path).unwrap();
         let mut processed_packages: Vec<PathBuf> = Vec::new();
 
+        for dependency in dependencies {
+            let dependency_path = dependency.get_path();
+
+            f(package, Box::new(dependency), &processed_packages)?;
+            processed_packages.push(dependency_path);
+        }
+        Ok(())
+    }
+
+    /// The registered packages and the dependencies between them, limited to the given package and
+    /// the packages it depends on if one is given
+    pub fn get_graph(&self, from: Option<&Path>) -> Result<Graph> {
+        let paths: Vec<PathBuf> = match from {
+            Some(path) => self.dependency_order(path)?,
+            None => self.packages.keys().cloned().collect(),
+        };
+        let nodes = paths
+            .iter()
+            .map(|path| (self.packages[path].get_name(), path.clone()))
+            .collect();
+        let edges: Vec<(PathBuf, PathBuf, Vec<DependencyType>)> = paths
+            .iter()
+            .flat_map(|path| {
+                self.edges[path]
+                    .iter()
+                    .map(move |(dependency, dependency_types)| {
+                        (path.clone(), dependency.clone(), dependency_types.clone())
+                    })
+            })
+            .collect();
+        Ok(Graph::new(nodes, &edges))
+    }
+
+    /// The given package and every registered package it depends on, directly or indirectly, with
+    /// dependencies ahead of their dependents
+    pub fn dependency_order(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        self.get_package(path)?;
+        let mut cycles = Vec::new();
+        self.find_cycles_from(path, &mut Vec::new(), &mut HashSet::new(), &mut cycles);
+        if let Some(cycle) = cycles.first() {
+            bail!("{}", self.describe_cycle(cycle));
+        }
         self.graph
-            .dependencies_of(&package.get_path())
-            .expect(format!("{:?} to have dependencies", package.get_path()).as_str())
-            .filter(|dependency_path_result| **dependency_path_result.as_ref().unwrap() != path)
-            .for_each(|dependency_path_result| {
-                let dependency_path = dependency_path_result.unwrap().to_path_buf();
-                let dependency = Box::new(Typescript::new(dependency_path.clone()));
+            .dependencies_of(&path.to_path_buf())?
+            .map(|dependency_path_result| dependency_path_result.map(|p| p.to_path_buf()))
+            .collect::<Result<Vec<PathBuf>, _>>()
+            .with_context(|| format!("Unable to resolve dependencies of {:?}", path))
+    }
+
+    /// Every registered package, with dependencies ahead of their dependents
+    fn topological_order(&self) -> Result<Vec<PathBuf>> {
+        let paths: Vec<PathBuf> = self.packages.keys().cloned().collect();
+        self.order_of(&paths)
+    }
+
+    /// The given packages and every registered package they depend on, directly or indirectly,
+    /// with dependencies ahead of their dependents
+    fn order_of(&self, paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+        let mut paths = paths.to_vec();
+        paths.sort();
+
+        let mut order: Vec<PathBuf> = Vec::new();
+        for path in paths {
+            for dependency in self.dependency_order(&path)? {
+                if !order.contains(&dependency) {
+                    order.push(dependency);
+                }
+            }
+        }
+        Ok(order)
+    }
+
+    /// Every registered package depending on the package, directly or indirectly
+    fn dependents_of(&self, path: &Path) -> Vec<PathBuf> {
+        let mut dependents: Vec<PathBuf> = Vec::new();
+        let mut queue = vec![path.to_path_buf()];
+
+        while let Some(dependency) = queue.pop() {
+            for (dependent, dependencies) in &self.edges {
+                if dependencies.contains_key(&dependency) && !dependents.contains(dependent) {
+                    dependents.push(dependent.clone());
+                    queue.push(dependent.clone());
+                }
+            }
+        }
+        dependents.sort();
+        dependents
+    }
 
-                f(package, dependency.clone(), &processed_packages);
-                processed_packages.push(dependency.get_path());
-            });
+    /// A fresh instance of the registered package, reading its package.json as it is now
+    fn instantiate(&self, path: &Path) -> Typescript {
+        let options = self.packages[path].get_options().clone();
+        Typescript::with_options(path.to_path_buf(), options)
     }
 
-    fn copy_tarball(dependency: &Bundle, package: Box<dyn Package>) {
+    fn get_package(&self, path: &Path) -> Result<&dyn Package> {
+        self.packages
+            .get(path)
+            .map(|package| &**package)
+            .ok_or_else(|| anyhow!("Package {:?} is not registered", path))
+    }
+
+    fn copy_tarball(
+        runner: &dyn Runner,
+        dependency: &Bundle,
+        package: Box<dyn Package>,
+    ) -> Result<()> {
         let mut package_build_path = package.get_path();
         package_build_path.push(dependency.get_local_bundle_file());
-        let mut package_build_dir_path = package_build_path.clone();
-        package_build_dir_path.pop();
 
-        fs::create_dir_all(package_build_dir_path).expect("Unable to create tmp dir");
-        fs::copy(dependency.get_tarball_file(), package_build_path)
-            .expect("Unable to copy tarball");
+        let tarball = if runner.is_dry_run() {
+            Vec::new()
+        } else {
+            fs::read(dependency.get_tarball_file()).context("Unable to read tarball")?
+        };
+        runner
+            .write(&package_build_path, &tarball)
+            .context("Unable to copy tarball")
     }
 }
 
 type Packages = HashMap<PathBuf, Box<dyn Package>>;
+
+/// The names and paths of packages matching a key, for an ambiguity error
+fn describe_matches(matches: &[(String, PathBuf)]) -> String {
+    matches
+        .iter()
+        .map(|(name, path)| format!("{} ({:?})", name, path))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
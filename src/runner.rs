@@ -0,0 +1,207 @@
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use std::thread;
+
+/// Performs the side effects of lpm operations: running commands and writing files
+pub trait Runner: Send + Sync {
+    /// Run the shell command in the given directory, returning its output if it was run
+    fn run(&self, command: &str, dir: &Path, label: &str) -> Result<Option<Output>>;
+    /// Replace the contents of the file
+    fn write(&self, path: &Path, contents: &[u8]) -> Result<()>;
+    /// Copy the file or directory, replacing whatever is at the destination
+    fn copy(&self, from: &Path, to: &Path) -> Result<()>;
+    /// Create a symlink to the original, replacing whatever is at the link's path
+    fn symlink(&self, original: &Path, link: &Path) -> Result<()>;
+    /// Remove the file, symlink or directory if it exists
+    fn remove(&self, path: &Path) -> Result<()>;
+    fn is_dry_run(&self) -> bool;
+
+    /// Keep the original contents of the file before it is rewritten, so they can be restored
+    fn record_original(&self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    /// Run the command, failing with the given error and the command's stderr if it could not be
+    /// run or exited unsuccessfully
+    fn run_checked(
+        &self,
+        command: &str,
+        dir: &Path,
+        label: &str,
+        error: &str,
+    ) -> Result<Option<Output>> {
+        let output = self
+            .run(command, dir, label)
+            .with_context(|| error.to_string())?;
+
+        if let Some(output) = &output {
+            debug!("{} -- for command `{}`", output.status, command);
+            if !output.status.success() {
+                bail!(
+                    "{}: `{}` exited with {}\n{}",
+                    error,
+                    command,
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim_end()
+                );
+            }
+        }
+        Ok(output)
+    }
+}
+
+/// Runs commands with `sh` and writes files to disk
+pub struct Shell;
+
+impl Runner for Shell {
+    /// Run the command, logging each line of its output prefixed with the given label as it
+    /// arrives
+    fn run(&self, command: &str, dir: &Path, label: &str) -> Result<Option<Output>> {
+        debug!("running command: {:?} in {:?}", command, dir);
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .current_dir(dir)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let stdout = stream_lines(child.stdout.take().unwrap(), label.to_string());
+        let stderr = stream_lines(child.stderr.take().unwrap(), label.to_string());
+        let status = child.wait()?;
+
+        Ok(Some(Output {
+            status,
+            stdout: stdout.join().unwrap_or_default(),
+            stderr: stderr.join().unwrap_or_default(),
+        }))
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> Result<()> {
+        debug!("Writing {:?}", path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, contents)?;
+        Ok(())
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+        debug!("Copying {:?} to {:?}", from, to);
+        self.remove(to)?;
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        copy_all(from, to).with_context(|| format!("Unable to copy {:?} to {:?}", from, to))
+    }
+
+    fn symlink(&self, original: &Path, link: &Path) -> Result<()> {
+        debug!("Linking {:?} to {:?}", link, original);
+        self.remove(link)?;
+        if let Some(parent) = link.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        create_symlink(original, link)
+            .with_context(|| format!("Unable to link {:?} to {:?}", link, original))
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(()),
+        };
+
+        debug!("Removing {:?}", path);
+        if metadata.is_dir() {
+            fs::remove_dir_all(path)?;
+        } else if cfg!(windows) && metadata.file_type().is_symlink() {
+            // symlinks to directories are removed as directories on Windows
+            fs::remove_dir(path).or_else(|_| fs::remove_file(path))?;
+        } else {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn is_dry_run(&self) -> bool {
+        false
+    }
+}
+
+/// Prints the commands and writes it is given instead of performing them
+pub struct DryRun;
+
+impl Runner for DryRun {
+    fn run(&self, command: &str, dir: &Path, label: &str) -> Result<Option<Output>> {
+        println!("{}: run `{}` in {:?}", label, command, dir);
+        Ok(None)
+    }
+
+    fn write(&self, path: &Path, _contents: &[u8]) -> Result<()> {
+        println!("write {:?}", path);
+        Ok(())
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+        println!("copy {:?} to {:?}", from, to);
+        Ok(())
+    }
+
+    fn symlink(&self, original: &Path, link: &Path) -> Result<()> {
+        println!("link {:?} to {:?}", link, original);
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        if path.exists() {
+            println!("remove {:?}", path);
+        }
+        Ok(())
+    }
+
+    fn is_dry_run(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(unix)]
+fn create_symlink(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(windows)]
+fn create_symlink(original: &Path, link: &Path) -> io::Result<()> {
+    // only package directories are linked
+    std::os::windows::fs::symlink_dir(original, link)
+}
+
+fn copy_all(from: &Path, to: &Path) -> Result<()> {
+    if !from.is_dir() {
+        fs::copy(from, to)?;
+        return Ok(());
+    }
+
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        copy_all(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+fn stream_lines(output: impl Read + Send + 'static, label: String) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut collected = Vec::new();
+        for line in BufReader::new(output).split(b'\n').map_while(Result::ok) {
+            info!("{} | {}", label, String::from_utf8_lossy(&line).trim_end());
+            collected.extend(line);
+            collected.push(b'\n');
+        }
+        collected
+    })
+}
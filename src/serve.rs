@@ -0,0 +1,309 @@
+use crate::cache;
+use crate::npmrc::NpmConfig;
+use crate::package::{Bundle, DependencyType, Package, Typescript};
+use crate::registry::Registry;
+use crate::runner::Shell;
+use crate::state::State;
+use anyhow::{anyhow, Context, Result};
+use blake2::{Blake2b, Digest};
+use log::{debug, error, info};
+use reqwest::blocking::Client;
+use semver::Version;
+use serde_json::{json, Value};
+use sha2::Sha512;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::Cursor;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// A registered package as served: its stamped version, package.json and tarball
+struct Release {
+    version: String,
+    manifest: Value,
+    tarball: Vec<u8>,
+}
+
+/// Releases by package name
+type Releases = Mutex<HashMap<String, Release>>;
+
+struct LocalRegistry<'a> {
+    /// The registry requests for other packages are passed through to, unless their scope has a
+    /// registry of its own, ending with a slash
+    upstream: String,
+    config: NpmConfig,
+    client: Client,
+    releases: &'a Releases,
+    /// Asks the refresher thread to prepare and bundle the packages which changed
+    refresh_requests: Sender<()>,
+}
+
+/// Serve every registered package from an npm registry on the port, passing requests for other
+/// packages through to the registry configured for their scope, or else the upstream registry.
+/// Packages changed since they were last served are prepared and bundled again in the background
+/// when their document is requested, serving their last release meanwhile. Runs until lpm is
+/// stopped
+pub fn serve(
+    registry: &Registry,
+    state: &mut State,
+    port: u16,
+    upstream: Option<&str>,
+) -> Result<()> {
+    let config = NpmConfig::load(&env::current_dir()?)?;
+    let upstream = match upstream {
+        Some(upstream) if upstream.ends_with('/') => upstream.to_string(),
+        Some(upstream) => format!("{}/", upstream),
+        None => config.get_default_registry(),
+    };
+    let releases = Mutex::new(HashMap::new());
+    refresh(registry, state, &releases)?;
+    state.store();
+
+    let server = Server::http(("127.0.0.1", port))
+        .map_err(|e| anyhow!("Unable to listen on port {}: {}", port, e))?;
+    info!(
+        "Serving {} packages at http://localhost:{}/, passing other requests through to {}",
+        releases.lock().unwrap().len(),
+        port,
+        upstream
+    );
+
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|scope| {
+        scope.spawn(|| refresh_on_request(registry, state, &releases, receiver));
+
+        let local = LocalRegistry {
+            upstream,
+            config,
+            client: Client::new(),
+            releases: &releases,
+            refresh_requests: sender,
+        };
+        for request in server.incoming_requests() {
+            let response = match local.handle(&request) {
+                Ok(response) => response,
+                Err(e) => {
+                    error!("{} {}: {:?}", request.method(), request.url(), e);
+                    Response::from_string(format!("{:?}", e)).with_status_code(500)
+                }
+            };
+            if let Err(e) = request.respond(response) {
+                error!("Unable to respond: {:?}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Refresh the releases whenever asked to, until the server stops. Failures are logged, keeping
+/// the last good releases
+fn refresh_on_request(
+    registry: &Registry,
+    state: &mut State,
+    releases: &Releases,
+    requests: Receiver<()>,
+) {
+    while requests.recv().is_ok() {
+        // requests made while refreshing are covered by the next refresh
+        while requests.try_recv().is_ok() {}
+        match refresh(registry, state, releases) {
+            Ok(()) => state.store(),
+            Err(e) => error!("Unable to refresh packages: {:?}", e),
+        }
+    }
+}
+
+/// Prepare the packages which changed, then bundle those whose stamped version changed
+fn refresh(registry: &Registry, state: &mut State, releases: &Releases) -> Result<()> {
+    let order = registry.prepare_changed(state)?;
+    // stamped versions by package name
+    let mut versions: HashMap<String, String> = HashMap::new();
+
+    for path in order {
+        let options = state.get_package_options(&path);
+        let package = Typescript::with_options(path.clone(), options.clone());
+        let name = package.get_name();
+        let mut manifest: Value = serde_json::from_slice(&fs::read(path.join("package.json"))?)
+            .with_context(|| format!("Unable to read package.json of {}", name))?;
+
+        // the stamp changes with the package's sources and the releases it depends on
+        let mut hasher = Blake2b::new();
+        hasher.input(cache::get_package_hash(&path, &options)?);
+        for dependency_type in &DependencyType::ALL {
+            if let Some(Value::Object(dependencies)) = manifest.get_mut(dependency_type.get_key()) {
+                for (dependency, spec) in dependencies.iter_mut() {
+                    if let Some(version) = versions.get(dependency) {
+                        *spec = Value::String(version.clone());
+                        hasher.input(dependency);
+                        hasher.input(version);
+                    }
+                }
+            }
+        }
+
+        let base = manifest["version"]
+            .as_str()
+            .and_then(|version| Version::parse(version).ok())
+            .unwrap_or_else(|| Version::new(0, 0, 0));
+        // the hash is prefixed with a letter, as an identifier of only digits with a leading zero
+        // is not valid semver
+        let version = format!(
+            "{}.{}.{}-lpm.g{}",
+            base.major,
+            base.minor,
+            base.patch,
+            &hex::encode(hasher.result())[..12]
+        );
+        manifest["version"] = Value::String(version.clone());
+        versions.insert(name.clone(), version.clone());
+
+        if let Some(release) = releases.lock().unwrap().get(&name) {
+            if release.version == version {
+                continue;
+            }
+        }
+
+        // bundled without holding the lock, so requests are served meanwhile
+        let bundle = Bundle::with_manifest(Box::new(package), manifest.clone());
+        bundle.prepare(&Shell)?;
+        let tarball = fs::read(bundle.get_tarball_file())
+            .with_context(|| format!("Unable to read tarball of {}", name))?;
+        info!("Bundled {}@{}", name, version);
+        releases.lock().unwrap().insert(
+            name,
+            Release {
+                version,
+                manifest,
+                tarball,
+            },
+        );
+    }
+    Ok(())
+}
+
+impl<'a> LocalRegistry<'a> {
+    fn handle(&self, request: &Request) -> Result<Response<Cursor<Vec<u8>>>> {
+        debug!("{} {}", request.method(), request.url());
+        let path = decode_path(request.url());
+        let releases = self.releases.lock().unwrap();
+
+        if let Some((name, file)) = path.split_once("/-/") {
+            if let Some(release) = releases.get(name) {
+                if file != get_tarball_name(name, &release.version) {
+                    return Ok(Response::from_string("Not found").with_status_code(404));
+                }
+                info!("Serving {}@{}", name, release.version);
+                return Ok(Response::from_data(release.tarball.clone())
+                    .with_header(get_header("Content-Type", "application/octet-stream")));
+            }
+        } else if let Some(release) = releases.get(&path) {
+            // the refresher thread is asked to catch up, while the last release is served
+            let _ = self.refresh_requests.send(());
+            let host = request
+                .headers()
+                .iter()
+                .find(|header| header.field.equiv("Host"))
+                .map(|header| header.value.to_string())
+                .unwrap_or_else(|| "localhost".to_string());
+            let document = get_document(&path, release, &format!("http://{}/", host));
+            return Ok(Response::from_string(document.to_string())
+                .with_header(get_header("Content-Type", "application/json")));
+        }
+
+        drop(releases);
+        self.pass_through(request)
+    }
+
+    fn pass_through(&self, request: &Request) -> Result<Response<Cursor<Vec<u8>>>> {
+        if *request.method() != Method::Get {
+            return Ok(Response::from_string("Method not allowed").with_status_code(405));
+        }
+
+        // scopes with a registry of their own are fetched from it, everything else from upstream
+        let registry = get_package_name(&decode_path(request.url()))
+            .and_then(|name| self.config.get_scope_registry(&name))
+            .unwrap_or_else(|| self.upstream.clone());
+        let url = format!("{}{}", registry, request.url().trim_start_matches('/'));
+        debug!("Passing through to {}", url);
+        let mut upstream_request = self.client.get(&url);
+        if let Some(accept) = request
+            .headers()
+            .iter()
+            .find(|header| header.field.equiv("Accept"))
+        {
+            upstream_request = upstream_request.header("Accept", accept.value.as_str());
+        }
+        if let Some(token) = self.config.get_auth_token(&registry) {
+            upstream_request = upstream_request.bearer_auth(token);
+        }
+
+        let response = upstream_request
+            .send()
+            .with_context(|| format!("Unable to fetch {}", url))?;
+        let status = response.status().as_u16();
+        let content_type = response
+            .headers()
+            .get("Content-Type")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("application/octet-stream")
+            .to_string();
+        let body = response.bytes()?.to_vec();
+        Ok(Response::from_data(body)
+            .with_status_code(status)
+            .with_header(get_header("Content-Type", &content_type)))
+    }
+}
+
+/// The registry document of the package, with its release as the only version
+fn get_document(name: &str, release: &Release, base_url: &str) -> Value {
+    let mut manifest = release.manifest.clone();
+    manifest["_id"] = json!(format!("{}@{}", name, release.version));
+    manifest["dist"] = json!({
+        "tarball": format!(
+            "{}{}/-/{}",
+            base_url,
+            name,
+            get_tarball_name(name, &release.version)
+        ),
+        "integrity": format!("sha512-{}", base64::encode(&Sha512::digest(&release.tarball))),
+    });
+
+    json!({
+        "name": name,
+        "dist-tags": { "latest": release.version, "lpm": release.version },
+        "versions": { release.version.clone(): manifest },
+    })
+}
+
+/// The request path without its leading slash or query, with scoped names decoded
+fn decode_path(url: &str) -> String {
+    let path = url.split('?').next().unwrap_or_default();
+    path.trim_start_matches('/')
+        .replace("%2f", "/")
+        .replace("%2F", "/")
+        .replace("%40", "@")
+}
+
+/// The name of the package a decoded request path is for, as in `@bitgo/statics` for
+/// `@bitgo/statics/-/statics-1.4.0.tgz`. None for requests which are not for a package
+fn get_package_name(path: &str) -> Option<String> {
+    let mut segments = path.split('/').filter(|segment| !segment.is_empty());
+    match segments.next()? {
+        "-" => None,
+        scope if scope.starts_with('@') => Some(format!("{}/{}", scope, segments.next()?)),
+        name => Some(name.to_string()),
+    }
+}
+
+/// The file name of the package's tarball, as in `statics-1.4.0.tgz` for `@bitgo/statics`
+fn get_tarball_name(name: &str, version: &str) -> String {
+    let base_name = name.rsplit('/').next().unwrap_or(name);
+    format!("{}-{}.tgz", base_name, version)
+}
+
+fn get_header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).unwrap()
+}
@@ -0,0 +1,165 @@
+use crate::runner::{Runner, Shell};
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::sync::Mutex;
+
+/// The original contents of every package.json rewritten since the session started
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Session {
+    started: String,
+    /// base64 encoded original bytes of each file
+    files: BTreeMap<PathBuf, String>,
+    session_file: PathBuf,
+}
+
+impl Session {
+    pub fn new(state_dir: PathBuf) -> Session {
+        Session {
+            started: Local::now().to_rfc2822(),
+            files: BTreeMap::new(),
+            session_file: Session::get_session_file(state_dir),
+        }
+    }
+
+    pub fn load(state_dir: PathBuf) -> Result<Option<Session>> {
+        let session_file = Session::get_session_file(state_dir);
+        if !session_file.exists() {
+            return Ok(None);
+        }
+
+        let file = File::open(&session_file)?;
+        let session: Session = serde_json::from_reader(file)
+            .with_context(|| format!("Unable to read session log {:?}", session_file))?;
+        Ok(Some(session))
+    }
+
+    pub fn store(&self) -> Result<()> {
+        let serialized = serde_json::to_string(&self)?;
+        let mut file = File::create(&self.session_file)?;
+        file.write_all(serialized.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn get_started(&self) -> &str {
+        &self.started
+    }
+
+    pub fn get_files(&self) -> Vec<PathBuf> {
+        self.files.keys().cloned().collect()
+    }
+
+    /// Record the contents of the file unless they were already recorded
+    pub fn record(&mut self, path: &Path) -> Result<()> {
+        if self.files.contains_key(path) {
+            return Ok(());
+        }
+
+        debug!("Recording original contents of {:?}", path);
+        let contents = fs::read(path)
+            .with_context(|| format!("Unable to record original contents of {:?}", path))?;
+        self.files
+            .insert(path.to_path_buf(), base64::encode(&contents));
+        // write through so the original is never lost, even if lpm fails later on
+        self.store()
+    }
+
+    /// Restore every recorded file to its original contents and end the session. Returns the
+    /// restored files
+    pub fn restore(mut self) -> Result<Vec<PathBuf>> {
+        let mut restored = Vec::new();
+        let mut failed = BTreeMap::new();
+
+        for (path, encoded) in std::mem::take(&mut self.files) {
+            match Session::restore_file(&path, &encoded) {
+                Ok(true) => restored.push(path),
+                Ok(false) => debug!("{:?} is unchanged", path),
+                Err(e) => {
+                    error!("Unable to restore {:?}: {:?}", path, e);
+                    failed.insert(path, encoded);
+                }
+            }
+        }
+
+        if !failed.is_empty() {
+            // keep the session around so the remaining files can be restored later
+            self.files = failed;
+            self.store()?;
+            bail!(
+                "Unable to restore {:?}, the session is still in progress",
+                self.get_files()
+            );
+        }
+
+        fs::remove_file(&self.session_file)?;
+        Ok(restored)
+    }
+
+    fn restore_file(path: &Path, encoded: &str) -> Result<bool> {
+        let original = base64::decode(encoded)?;
+        if fs::read(path).ok().as_ref() == Some(&original) {
+            return Ok(false);
+        }
+
+        info!("Restoring {:?}", path);
+        fs::write(path, original)?;
+        Ok(true)
+    }
+
+    fn get_session_file(state_dir: PathBuf) -> PathBuf {
+        let mut session_file = state_dir;
+        session_file.push("session.json");
+        session_file
+    }
+}
+
+/// Runs commands and writes files with the shell, recording the original contents of every
+/// package.json in the session before it is first rewritten
+pub struct Recorder {
+    session: Mutex<Session>,
+}
+
+impl Recorder {
+    pub fn new(session: Session) -> Recorder {
+        Recorder {
+            session: Mutex::new(session),
+        }
+    }
+}
+
+impl Runner for Recorder {
+    fn run(&self, command: &str, dir: &Path, label: &str) -> Result<Option<Output>> {
+        Shell.run(command, dir, label)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> Result<()> {
+        Shell.write(path, contents)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+        Shell.copy(from, to)
+    }
+
+    fn symlink(&self, original: &Path, link: &Path) -> Result<()> {
+        Shell.symlink(original, link)
+    }
+
+    fn remove(&self, path: &Path) -> Result<()> {
+        Shell.remove(path)
+    }
+
+    fn is_dry_run(&self) -> bool {
+        false
+    }
+
+    fn record_original(&self, path: &Path) -> Result<()> {
+        self.session.lock().unwrap().record(path)
+    }
+}
@@ -1,13 +1,24 @@
+use crate::cache::Cache;
+use crate::package::{DependencyType, PackageOptions};
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct State {
     pub package_paths: HashSet<PathBuf>,
+    #[serde(default)]
+    pub package_options: HashMap<PathBuf, PackageOptions>,
+    #[serde(default)]
+    pub cache: Cache,
+    #[serde(default)]
+    pub original_specs: OriginalSpecs,
+    /// Versions of the packages published by a publish run which has not finished yet, by path
+    #[serde(default)]
+    pub published: HashMap<PathBuf, String>,
     state_file: PathBuf,
 }
 
@@ -15,6 +26,10 @@ impl State {
     pub fn new(state_dir: PathBuf) -> State {
         State {
             package_paths: HashSet::new(),
+            package_options: HashMap::new(),
+            cache: Cache::new(),
+            original_specs: OriginalSpecs::default(),
+            published: HashMap::new(),
             state_file: State::get_state_file(state_dir),
         }
     }
@@ -22,20 +37,84 @@ impl State {
     pub fn store(&self) {
         let serialized = serde_json::to_string(&self).expect("to be able to serialize");
         let mut file = File::create(&self.state_file).expect("state file to  exist");
-        file.write_all(&serialized.as_bytes())
+        file.write_all(serialized.as_bytes())
             .expect("to be able to write to file");
     }
 
     pub fn load(state_dir: PathBuf) -> Result<State> {
-        let file = File::open(State::get_state_file(state_dir).clone())?;
+        let file = File::open(State::get_state_file(state_dir))?;
         let loaded_state: State = serde_json::from_reader(file)?;
 
         Ok(loaded_state)
     }
 
+    pub fn get_package_options(&self, path: &Path) -> PackageOptions {
+        self.package_options.get(path).cloned().unwrap_or_default()
+    }
+
     fn get_state_file(state_dir: PathBuf) -> PathBuf {
-        let mut state_file = state_dir.clone();
+        let mut state_file = state_dir;
         state_file.push("serialized.json");
         state_file
     }
 }
+
+/// Dependency specs as they were before lpm pointed them at local packages, by section, dependent
+/// path and dependency name
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct OriginalSpecs {
+    sections: HashMap<DependencyType, HashMap<PathBuf, HashMap<String, String>>>,
+}
+
+impl OriginalSpecs {
+    /// Record the spec a dependency had before lpm replaced it. Specs lpm wrote itself are never
+    /// recorded, so the first original is kept across repeated updates
+    pub fn record(
+        &mut self,
+        dependency_type: DependencyType,
+        dependent: &Path,
+        dependency_name: &str,
+        previous: &str,
+    ) {
+        if previous.starts_with("file:") {
+            return;
+        }
+
+        self.sections
+            .entry(dependency_type)
+            .or_default()
+            .entry(dependent.to_path_buf())
+            .or_default()
+            .insert(dependency_name.to_string(), previous.to_string());
+    }
+
+    pub fn get(
+        &self,
+        dependency_type: DependencyType,
+        dependent: &Path,
+        dependency_name: &str,
+    ) -> Option<&String> {
+        self.sections
+            .get(&dependency_type)
+            .and_then(|section| section.get(dependent))
+            .and_then(|specs| specs.get(dependency_name))
+    }
+
+    pub fn remove(
+        &mut self,
+        dependency_type: DependencyType,
+        dependent: &Path,
+        dependency_name: &str,
+    ) {
+        let section = match self.sections.get_mut(&dependency_type) {
+            Some(section) => section,
+            None => return,
+        };
+        if let Some(specs) = section.get_mut(dependent) {
+            specs.remove(dependency_name);
+            if specs.is_empty() {
+                section.remove(dependent);
+            }
+        }
+    }
+}
@@ -1,24 +1,7 @@
+use anyhow::{Context, Result};
 use env_logger::Env;
-use log::debug;
 use std::fs;
-use std::path::PathBuf;
-use std::process::{Command, Output};
-
-pub fn run_basic_command(command: &str) -> Result<Output, std::io::Error> {
-    debug!("running command: {:?}", command);
-    Command::new("sh").arg("-c").arg(command).output()
-}
-
-pub fn run_basic_command_expect(command: &str, error: &str) -> Output {
-    let result = run_basic_command(command);
-    let output = result.expect(error);
-
-    debug!(
-        "{} -- {:?} -- for command `{}`",
-        output.status, output, command
-    );
-    output
-}
+use std::path::{Component, Path, PathBuf};
 
 pub fn setup_env_logger(default_level: &str) {
     // always override with env if given
@@ -29,12 +12,40 @@ pub fn setup_env_logger_cli(v_occurrences: u64) {
     let log_level = match v_occurrences {
         0 => "info",
         1 => "debug",
-        2 | _ => "trace",
+        _ => "trace",
     };
 
     setup_env_logger(log_level);
 }
 
-pub fn get_path(path: &str) -> PathBuf {
-    fs::canonicalize(PathBuf::from(path)).expect(format!("Invalid path: {}", path).as_str())
+pub fn get_path(path: &str) -> Result<PathBuf> {
+    fs::canonicalize(PathBuf::from(path)).with_context(|| format!("Invalid path: {}", path))
+}
+
+/// The path leading from the directory to the target, both absolute
+pub fn get_relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from: Vec<Component> = from.components().collect();
+    let to: Vec<Component> = to.components().collect();
+    let common = from
+        .iter()
+        .zip(to.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut relative = PathBuf::new();
+    from[common..].iter().for_each(|_| relative.push(".."));
+    to[common..]
+        .iter()
+        .for_each(|component| relative.push(component));
+    if relative.as_os_str().is_empty() {
+        relative.push(".");
+    }
+    relative
+}
+
+/// The current user's home directory, if known
+pub fn get_home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .and_then(|home| fs::canonicalize(home).ok())
 }
@@ -0,0 +1,108 @@
+use crate::cache;
+use crate::package::PackageOptions;
+use crate::registry::Registry;
+use crate::state::State;
+use anyhow::{Context, Result};
+use log::{debug, error, info};
+use notify::{RecursiveMode, Watcher};
+use std::collections::{BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// Update the package, then watch it and every registered package it depends on. When files
+/// change, the changed packages are prepared again along with the packages depending on them.
+/// Changes arriving within the debounce time of each other are handled as one. Runs until lpm is
+/// stopped
+pub fn watch(
+    registry: &mut Registry,
+    path: PathBuf,
+    state: &mut State,
+    jobs: usize,
+    debounce: Duration,
+) -> Result<()> {
+    registry.update_dependencies(path.clone(), state, false, jobs)?;
+    state.store();
+
+    let packages = registry.dependency_order(&path)?;
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).context("Unable to watch files")?;
+    for package in &packages {
+        for (dir, mode) in get_watched_dirs(package, &state.get_package_options(package))? {
+            watcher
+                .watch(&dir, mode)
+                .with_context(|| format!("Unable to watch {:?}", dir))?;
+        }
+    }
+    info!("Watching {} packages for changes", packages.len());
+
+    loop {
+        let mut changed_files: HashSet<PathBuf> = HashSet::new();
+        // wait for a change, then for the changes following it to settle
+        let mut event = receiver.recv()?;
+        loop {
+            match event {
+                Ok(event) => changed_files.extend(event.paths),
+                Err(e) => error!("Error watching files: {:?}", e),
+            }
+            event = match receiver.recv_timeout(debounce) {
+                Ok(event) => event,
+                Err(mpsc::RecvTimeoutError::Timeout) => break,
+                Err(e) => return Err(e.into()),
+            };
+        }
+
+        // output of builds and installs is not hashed, so changes to it are skipped here
+        let changed: Vec<PathBuf> = packages
+            .iter()
+            .filter(|package| {
+                changed_files
+                    .iter()
+                    .any(|file| get_package_of(&packages, file) == Some(*package))
+            })
+            .filter(|package| registry.has_changed(package, &state.cache))
+            .cloned()
+            .collect();
+        if changed.is_empty() {
+            debug!("No changes to package inputs in {:?}", changed_files);
+            continue;
+        }
+
+        info!("Changed: {:?}", changed);
+        match registry.update_changed(&path, &changed, state, jobs) {
+            Ok(()) => info!("Up to date, watching for changes"),
+            // keep watching, the next change may fix it
+            Err(e) => error!("{:?}", e),
+        }
+        state.store();
+    }
+}
+
+/// The directories watched for changes to the package's source inputs: the package directory
+/// itself for the files directly in it, and recursively each top level directory containing
+/// inputs. Installed dependencies and build output are never inputs, so they are not watched
+fn get_watched_dirs(
+    path: &Path,
+    options: &PackageOptions,
+) -> Result<Vec<(PathBuf, RecursiveMode)>> {
+    let mut dirs = vec![(path.to_path_buf(), RecursiveMode::NonRecursive)];
+    let top_level: BTreeSet<PathBuf> = cache::get_hash_inputs(path, options)?
+        .into_iter()
+        .filter(|input| input.components().count() > 1)
+        .filter_map(|input| input.components().next().map(|dir| path.join(dir)))
+        .collect();
+    dirs.extend(
+        top_level
+            .into_iter()
+            .map(|dir| (dir, RecursiveMode::Recursive)),
+    );
+    Ok(dirs)
+}
+
+/// The innermost of the packages containing the file
+fn get_package_of<'a>(packages: &'a [PathBuf], file: &Path) -> Option<&'a PathBuf> {
+    packages
+        .iter()
+        .filter(|package| file.starts_with(package))
+        .max_by_key(|package| package.components().count())
+}